@@ -0,0 +1,231 @@
+use std::{
+    env,
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+    process::Command,
+    str::FromStr,
+};
+
+use crate::arch::Arch;
+
+/// QEMU accelerator used to run the guest
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Accel {
+    /// Linux kernel virtual machine
+    Kvm,
+    /// macOS Hypervisor.framework
+    Hvf,
+    /// Windows Hypervisor Platform
+    Whpx,
+    /// Emulation, available everywhere but much slower
+    Tcg,
+}
+
+impl Accel {
+    pub const ALL: [Accel; 4] = [Accel::Kvm, Accel::Hvf, Accel::Whpx, Accel::Tcg];
+
+    /// Name used by QEMU for this accelerator
+    pub fn name(&self) -> &'static str {
+        match self {
+            Accel::Kvm => "kvm",
+            Accel::Hvf => "hvf",
+            Accel::Whpx => "whpx",
+            Accel::Tcg => "tcg",
+        }
+    }
+
+    /// Check if the host is able to use this accelerator, returning the reason if not
+    fn available(&self) -> Result<(), String> {
+        match self {
+            Accel::Kvm => {
+                if !cfg!(target_os = "linux") {
+                    return Err("KVM is only available on Linux".to_string());
+                }
+                open_kvm()
+                    .map(|_| ())
+                    .map_err(|err| format!("failed to open /dev/kvm: {err}"))
+            }
+            Accel::Hvf => {
+                if !cfg!(target_os = "macos") {
+                    return Err("HVF is only available on macOS".to_string());
+                }
+                let output = Command::new("sysctl")
+                    .arg("-n")
+                    .arg("kern.hv_support")
+                    .output()
+                    .map_err(|err| format!("failed to run sysctl: {err}"))?;
+                if String::from_utf8_lossy(&output.stdout).trim() == "1" {
+                    Ok(())
+                } else {
+                    Err("Hypervisor.framework is not supported".to_string())
+                }
+            }
+            Accel::Whpx => {
+                if !cfg!(windows) {
+                    return Err("WHPX is only available on Windows".to_string());
+                }
+                let system_root = env::var_os("SystemRoot").unwrap_or("C:\\Windows".into());
+                if Path::new(&system_root)
+                    .join("System32")
+                    .join("WinHvPlatform.dll")
+                    .is_file()
+                {
+                    Ok(())
+                } else {
+                    Err("Windows Hypervisor Platform is not enabled".to_string())
+                }
+            }
+            Accel::Tcg => Ok(()),
+        }
+    }
+
+    /// Pick the best accelerator available for the architecture and QEMU binary, skipping KVM
+    /// if no_kvm is set
+    ///
+    /// Fails if KVM exists but the user cannot access it, as that is usually fixed by joining
+    /// the kvm group instead of running much slower with TCG
+    pub fn detect(arch: Arch, qemu_system: &OsStr, no_kvm: bool) -> Result<Accel, String> {
+        if !arch.is_native() {
+            log::info!("using TCG, as {arch} is not the host architecture");
+            return Ok(Accel::Tcg);
+        }
+
+        let supported = supported(qemu_system);
+        let mut reasons = Vec::new();
+        for accel in [Accel::Kvm, Accel::Hvf, Accel::Whpx] {
+            if accel == Accel::Kvm && no_kvm {
+                reasons.push("KVM disabled by --no-kvm".to_string());
+                continue;
+            }
+            if let Some(supported) = &supported {
+                if !supported.contains(&accel) {
+                    reasons.push(format!("QEMU does not support {}", accel.name()));
+                    continue;
+                }
+            }
+            if accel == Accel::Kvm {
+                check_kvm_access()?;
+            }
+            match accel.available() {
+                Ok(()) => return Ok(accel),
+                Err(reason) => reasons.push(reason),
+            }
+        }
+
+        log::warn!(
+            "no hardware acceleration available, using TCG which is much slower: {}",
+            reasons.join(", ")
+        );
+        Ok(Accel::Tcg)
+    }
+
+    /// Add the accelerator and a matching CPU model unless one is given, with an optional CPU
+    /// feature flag
+    pub fn args(
+        &self,
+        command: &mut Command,
+        arch: Arch,
+        cpu_model: Option<&str>,
+        cpu_flag: Option<&str>,
+    ) {
+        command.arg("-accel").arg(self.name());
+        let cpu = match (cpu_model, self) {
+            (Some(cpu_model), _) => cpu_model,
+            (None, Accel::Kvm | Accel::Hvf) => "host",
+            (None, Accel::Whpx | Accel::Tcg) => arch.emulated_cpu(),
+        };
+        match cpu_flag {
+            Some(flag) => command.arg("-cpu").arg(format!("{cpu},+{flag}")),
+            None => command.arg("-cpu").arg(cpu),
+        };
+    }
+}
+
+fn open_kvm() -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+}
+
+/// Fail with instructions for fixing access if /dev/kvm exists but cannot be opened
+pub fn check_kvm_access() -> Result<(), String> {
+    match open_kvm() {
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+            let user = env::var("USER").unwrap_or_else(|_| "$USER".to_string());
+            Err(format!(
+                "/dev/kvm exists but you do not have access to it. Add your user to the kvm group \
+                 with `sudo usermod -aG kvm {user}`, then log out and back in for it to take \
+                 effect. Or run with --no-kvm to use TCG, which is much slower"
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// CPU feature flag that exposes virtualization to the guest, after checking that the host
+/// allows nested virtualization
+pub fn nested_flag(accel: Accel, arch: Arch) -> Result<&'static str, String> {
+    if accel != Accel::Kvm || !matches!(arch, Arch::X86_64 | Arch::I686) {
+        return Err("nested virtualization requires KVM and an x86 guest".to_string());
+    }
+    // The module of the host CPU vendor reports if nesting is enabled
+    for (module, flag) in [("kvm_intel", "vmx"), ("kvm_amd", "svm")] {
+        let path = format!("/sys/module/{module}/parameters/nested");
+        if let Ok(nested) = fs::read_to_string(&path) {
+            return match nested.trim() {
+                "Y" | "1" => Ok(flag),
+                _ => Err(format!(
+                    "nested virtualization is disabled, enable it with \
+                     `echo 'options {module} nested=1' | sudo tee /etc/modprobe.d/{module}.conf` \
+                     and reload the {module} module"
+                )),
+            };
+        }
+    }
+    Err("KVM module not found, unable to check for nested virtualization".to_string())
+}
+
+/// Accelerators built into a QEMU binary, or None if they cannot be listed
+fn supported(qemu_system: &OsStr) -> Option<Vec<Accel>> {
+    // Output is a header followed by one accelerator per line
+    let output = Command::new(qemu_system)
+        .arg("-accel")
+        .arg("help")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect(),
+    )
+}
+
+impl fmt::Display for Accel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Accel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kvm" => Ok(Accel::Kvm),
+            "hvf" => Ok(Accel::Hvf),
+            "whpx" => Ok(Accel::Whpx),
+            "tcg" => Ok(Accel::Tcg),
+            _ => Err(format!(
+                "unknown accelerator {s:?}, expected one of {}",
+                Accel::ALL.map(|accel| accel.name()).join(", ")
+            )),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::profile::Profile;
+
+/// Name of the virtio-serial port, which the guest uses to find the channel
+pub const PORT_NAME: &str = "org.redox-os.rebox.0";
+
+/// Path of the host side of the channel of the VM for a profile, or the VM without a profile
+pub fn socket_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "agent")
+}
+
+/// Add a virtio-serial port connected to a socket at path, which rebox connects to in order
+/// to talk with an agent in the guest
+pub fn args(command: &mut Command, path: &Path) {
+    command.arg("-device").arg("virtio-serial-pci,id=agent");
+    // Remains available after a client disconnects, for the next one
+    command.arg("-chardev").arg(format!(
+        "socket,id=agent0,path={},server=on,wait=off",
+        path.display()
+    ));
+    command.arg("-device").arg(format!(
+        "virtserialport,bus=agent.0,chardev=agent0,name={PORT_NAME}"
+    ));
+}
+
+/// Connection to the channel of a running VM
+#[cfg(unix)]
+pub fn connect(path: &Path) -> Result<std::os::unix::net::UnixStream, String> {
+    std::os::unix::net::UnixStream::connect(path).map_err(|err| {
+        format!(
+            "failed to connect to {}, is the VM running with --agent? {err}",
+            path.display()
+        )
+    })
+}
+
+/// Connect standard input and output to the channel of a running VM
+#[cfg(unix)]
+pub fn attach(path: &Path) -> Result<(), Box<dyn Error>> {
+    use std::{io, thread};
+
+    let stream = connect(path)?;
+    let mut writer = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut io::stdin().lock(), &mut writer);
+        // Let the guest know that there is no more input
+        let _ = writer.shutdown(std::net::Shutdown::Write);
+    });
+    io::copy(&mut &stream, &mut io::stdout().lock())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn attach(_path: &Path) -> Result<(), Box<dyn Error>> {
+    Err("the guest channel is only supported on Unix hosts".into())
+}
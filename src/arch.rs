@@ -0,0 +1,263 @@
+use std::{fmt, process::Command, str::FromStr};
+
+use crate::{
+    audio::Audio,
+    gpu::{Gpu, Resolution, Vga},
+};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Arch {
+    #[default]
+    X86_64,
+    I686,
+    Aarch64,
+    Riscv64gc,
+}
+
+impl Arch {
+    pub const ALL: [Arch; 4] = [Arch::X86_64, Arch::I686, Arch::Aarch64, Arch::Riscv64gc];
+
+    /// Name used by Redox for this architecture
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::I686 => "i686",
+            Arch::Aarch64 => "aarch64",
+            Arch::Riscv64gc => "riscv64gc",
+        }
+    }
+
+    /// QEMU system emulator used to run this architecture
+    pub fn qemu_system(&self) -> &'static str {
+        match self {
+            // qemu-system-x86_64 is able to run i686 guests
+            Arch::X86_64 | Arch::I686 => "qemu-system-x86_64",
+            Arch::Aarch64 => "qemu-system-aarch64",
+            Arch::Riscv64gc => "qemu-system-riscv64",
+        }
+    }
+
+    /// Returns true if the host CPU can run this architecture with hardware virtualization
+    pub fn is_native(&self) -> bool {
+        match self {
+            Arch::X86_64 | Arch::I686 => cfg!(target_arch = "x86_64"),
+            Arch::Aarch64 => cfg!(target_arch = "aarch64"),
+            Arch::Riscv64gc => cfg!(target_arch = "riscv64"),
+        }
+    }
+
+    /// Machine used when none is selected
+    pub fn default_machine(&self) -> Machine {
+        match self {
+            Arch::X86_64 => Machine::Q35,
+            Arch::I686 => Machine::Pc,
+            Arch::Aarch64 | Arch::Riscv64gc => Machine::Virt,
+        }
+    }
+
+    pub fn supports(&self, machine: Machine) -> bool {
+        match self {
+            Arch::X86_64 => matches!(machine, Machine::Q35 | Machine::Pc | Machine::Microvm),
+            Arch::I686 => matches!(machine, Machine::Q35 | Machine::Pc),
+            Arch::Aarch64 | Arch::Riscv64gc => machine == Machine::Virt,
+        }
+    }
+
+    /// CPU model used when hardware virtualization is not available
+    pub fn emulated_cpu(&self) -> &'static str {
+        match self {
+            Arch::I686 => "pentium2",
+            _ => "max",
+        }
+    }
+
+    /// Firmware loaded with -bios, found in the QEMU data directory
+    pub fn firmware(&self) -> Option<&'static str> {
+        match self {
+            Arch::X86_64 | Arch::I686 => None,
+            Arch::Aarch64 => Some("edk2-aarch64-code.fd"),
+            Arch::Riscv64gc => Some("edk2-riscv-code.fd"),
+        }
+    }
+
+    /// UEFI firmware code and the template of its variable store, loaded as pflash with --uefi
+    pub fn uefi_firmware(&self) -> (&'static str, &'static str) {
+        match self {
+            Arch::X86_64 => ("edk2-x86_64-code.fd", "edk2-i386-vars.fd"),
+            Arch::I686 => ("edk2-i386-code.fd", "edk2-i386-vars.fd"),
+            Arch::Aarch64 => ("edk2-aarch64-code.fd", "edk2-arm-vars.fd"),
+            Arch::Riscv64gc => ("edk2-riscv-code.fd", "edk2-riscv-vars.fd"),
+        }
+    }
+
+    /// Returns true if an audio device is added for this architecture and machine
+    pub fn has_audio(&self, machine: Machine) -> bool {
+        matches!(self, Arch::X86_64 | Arch::I686) && machine != Machine::Microvm
+    }
+
+    /// Add the audio device for this architecture and machine, connecting its output to a
+    /// host audio backend if one is given
+    pub fn audio(&self, command: &mut Command, machine: Machine, audio: Option<Audio>) {
+        if !self.has_audio(machine) {
+            return;
+        }
+
+        // HDA audio device
+        command.arg("-device").arg("ich9-intel-hda");
+        match audio {
+            Some(audio) => {
+                command
+                    .arg("-audiodev")
+                    .arg(format!("{},id=snd0", audio.driver()));
+                command.arg("-device").arg("hda-output,audiodev=snd0");
+            }
+            None => {
+                command.arg("-device").arg("hda-output");
+            }
+        }
+    }
+
+    /// Add the graphics device, selecting the VGA device on x86 and the preferred resolution if
+    /// given
+    pub fn gpu(
+        &self,
+        command: &mut Command,
+        gpu: Gpu,
+        vga: Option<Vga>,
+        resolution: Option<Resolution>,
+    ) {
+        let x86 = matches!(self, Arch::X86_64 | Arch::I686);
+        let props = resolution.map(|resolution| resolution.props());
+        let device = match gpu {
+            // VGA device is provided by the machine unless it needs to be configured
+            Gpu::Std if x86 => match (vga.unwrap_or_default(), props) {
+                (Vga::Std, None) if vga.is_none() => None,
+                (vga, Some(props)) if vga.has_resolution() => {
+                    // EDID is needed for the standard VGA device to report the resolution
+                    let edid = if vga == Vga::Std { ",edid=on" } else { "" };
+                    Some(format!("{}{edid}{props}", vga.device()))
+                }
+                (vga, props) => {
+                    if props.is_some() {
+                        log::warn!("ignoring --resolution, {vga} VGA cannot report it");
+                    }
+                    Some(vga.device().to_string())
+                }
+            },
+            // Simple framebuffer, as there is no VGA device
+            Gpu::Std => {
+                if props.is_some() {
+                    log::warn!("ignoring --resolution, the {self} framebuffer cannot report it");
+                }
+                Some("ramfb".to_string())
+            }
+            Gpu::Virtio => Some(format!("virtio-gpu-pci{}", props.unwrap_or_default())),
+            Gpu::Virgl => Some(format!("virtio-gpu-gl-pci{}", props.unwrap_or_default())),
+        };
+        if x86 && device.is_some() {
+            // Replace the VGA device of the machine
+            command.arg("-vga").arg("none");
+        }
+        if let Some(device) = device {
+            command.arg("-device").arg(device);
+        }
+    }
+
+    /// Check if the default devices include a USB controller
+    pub fn has_usb(&self) -> bool {
+        matches!(self, Arch::Aarch64 | Arch::Riscv64gc)
+    }
+
+    /// Add the default devices for this architecture, other than graphics, audio, and network
+    pub fn devices(&self, command: &mut Command) {
+        match self {
+            // PS/2 devices are provided by the machine
+            Arch::X86_64 | Arch::I686 => {}
+            Arch::Aarch64 | Arch::Riscv64gc => {
+                // USB keyboard and tablet, as there is no PS/2 controller
+                command.arg("-device").arg("qemu-xhci");
+                command.arg("-device").arg("usb-kbd");
+                command.arg("-device").arg("usb-tablet");
+            }
+        }
+    }
+}
+
+/// QEMU machine type
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Machine {
+    Q35,
+    Pc,
+    Virt,
+    /// Minimal x86_64 machine without legacy devices, which boots faster
+    Microvm,
+}
+
+impl Machine {
+    pub const ALL: [Machine; 4] = [Machine::Q35, Machine::Pc, Machine::Virt, Machine::Microvm];
+
+    /// Name used by QEMU for this machine
+    pub fn name(&self) -> &'static str {
+        match self {
+            Machine::Q35 => "q35",
+            Machine::Pc => "pc",
+            Machine::Virt => "virt",
+            Machine::Microvm => "microvm",
+        }
+    }
+
+    /// Argument for -machine, with the options needed by Redox
+    pub fn arg(&self) -> &'static str {
+        match self {
+            // PCIe is needed for the virtio devices that Redox has drivers for
+            Machine::Microvm => "microvm,pcie=on,rtc=on",
+            _ => self.name(),
+        }
+    }
+}
+
+impl fmt::Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Machine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "q35" => Ok(Machine::Q35),
+            "pc" => Ok(Machine::Pc),
+            "virt" => Ok(Machine::Virt),
+            "microvm" => Ok(Machine::Microvm),
+            _ => Err(format!(
+                "unknown machine {s:?}, expected one of {}",
+                Machine::ALL.map(|machine| machine.name()).join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Arch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" => Ok(Arch::X86_64),
+            "i686" => Ok(Arch::I686),
+            "aarch64" => Ok(Arch::Aarch64),
+            "riscv64gc" => Ok(Arch::Riscv64gc),
+            _ => Err(format!(
+                "unknown architecture {s:?}, expected one of {}",
+                Arch::ALL.map(|arch| arch.name()).join(", ")
+            )),
+        }
+    }
+}
@@ -0,0 +1,1011 @@
+use clap::{CommandFactory, Parser};
+use clap_complete::Shell;
+use std::{env, io, iter, path::PathBuf, time::Duration};
+
+use crate::{
+    accel::Accel,
+    arch::{Arch, Machine},
+    audio::Audio,
+    config,
+    disk::DiskInterface,
+    display::Display,
+    gpu::{Gpu, Resolution, Vga},
+    host,
+    image::{Channel, Variant},
+    net::{MacAddr, Network, Nic, NicModel, Publish, UserOptions},
+    output,
+    profile::Profile,
+    qemu, record,
+    rtc::Rtc,
+    serial::Serial,
+    share::Share,
+    usb::UsbDevice,
+    util, vnc,
+};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Subcommand {
+    /// Run the Redox image in QEMU, which is done without a command
+    Run {
+        /// Arguments passed to QEMU
+        #[arg(last = true, value_name = "QEMU ARGS")]
+        qemu_args: Vec<String>,
+    },
+    /// Download and prepare the harddrive, QEMU, and firmware without running the VM
+    Download,
+    /// List the images available for download
+    Images,
+    /// Manage snapshots of a qcow2 harddrive
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Remove downloads and partial files from the cache, keeping the harddrive selected by
+    /// the options
+    Clean(CleanOptions),
+    /// Check the hashes of all cached files
+    Verify {
+        /// Remove corrupted files and download or extract them again
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Inspect and clean the cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Grow the harddrive to SIZE, like 16G. The size is kept when the harddrive is reset or
+    /// updated
+    Resize {
+        #[arg(value_parser = util::parse_size)]
+        size: u64,
+    },
+    /// List the CPU models that can be used with --cpu-model
+    CpuModels,
+    /// Set the memory of the running VM by inflating or deflating the balloon added with
+    /// --balloon
+    Balloon {
+        /// Memory of the guest, like 2G, or in MiB without a unit
+        #[arg(value_parser = parse_mib)]
+        size: u64,
+    },
+    /// Connect standard input and output to the channel added to the running VM with --agent
+    Agent,
+    /// Debug the kernel of a VM started with --gdb
+    Gdb {
+        /// Kernel with symbols, instead of the one in the Redox source tree the image was
+        /// built in
+        #[arg(long, value_name = "PATH")]
+        kernel: Option<PathBuf>,
+    },
+    /// List the running VMs launched by rebox
+    Ps,
+    /// Connect the terminal to the serial console of a VM started with --detach, until Ctrl-]
+    /// is pressed
+    Attach {
+        /// Connect to the QEMU monitor instead
+        #[arg(long)]
+        monitor: bool,
+    },
+    /// Terminate a running VM and remove its sockets and state. Also cleans up after a VM
+    /// that crashed
+    Kill {
+        /// Profile name of the VM, default for the VM without a profile
+        #[arg(value_parser = parse_name)]
+        name: Option<String>,
+    },
+    /// Power off the running VM, quitting QEMU if the guest has not shut down within the
+    /// timeout
+    Stop {
+        /// Time to wait for the guest, like 30s or 2m, 60s by default
+        #[arg(long, value_name = "TIME", value_parser = util::parse_duration)]
+        timeout: Option<Duration>,
+    },
+    /// Pause the guest of the running VM
+    Pause,
+    /// Resume the guest of the running VM after pause
+    Resume,
+    /// Save the state of the running VM, including its memory, in a snapshot of the qcow2
+    /// harddrive
+    Save { name: String },
+    /// Load a state saved with save into the running VM
+    Restore { name: String },
+    /// Press a combination of keys in the running VM, like ctrl-alt-f2, using QEMU key names
+    #[command(name = "sendkey")]
+    SendKey { keys: String },
+    /// Type text in the running VM, with a US keyboard layout
+    Type {
+        #[arg(allow_hyphen_values = true)]
+        text: String,
+    },
+    /// Run a QMP command on the running VM and print the result
+    Qmp {
+        command: String,
+        /// Arguments of the command as a JSON object
+        arguments: Option<String>,
+    },
+    /// Save the display of the running VM to a PNG file
+    Screenshot {
+        /// Path of the PNG file, by default screenshot-<TIME>.png in the current directory
+        path: Option<PathBuf>,
+    },
+    /// Record the display of the running VM to a video, like demo.webm, with ffmpeg until
+    /// enter is pressed
+    Record {
+        path: PathBuf,
+        /// Frames captured per second
+        #[arg(long, default_value_t = record::DEFAULT_FPS, value_parser = clap::value_parser!(u32).range(1..=60))]
+        fps: u32,
+    },
+    /// Open an SSH session to the running VM, forwarding a port to the guest if there is none
+    Ssh {
+        /// User to log in as
+        #[arg(long, default_value = "user")]
+        user: String,
+        /// Arguments passed to ssh
+        #[arg(last = true, value_name = "SSH ARGS")]
+        args: Vec<String>,
+    },
+    /// Save the settings and harddrive of a profile to a tar.zst archive, to move the VM to
+    /// another machine
+    Export { path: PathBuf },
+    /// Create a profile from an exported archive, named after the exported profile unless
+    /// --profile is given
+    Import { path: PathBuf },
+    /// Build a harddrive image from a Redox source tree with make, using the variant as the
+    /// build config, and set the profile to run it
+    Build {
+        #[arg(value_name = "REDOX DIR")]
+        source_dir: PathBuf,
+    },
+    /// Print the completion script for a shell, like `rebox completions bash >
+    /// ~/.local/share/bash-completion/completions/rebox`
+    Completions { shell: Shell },
+}
+
+impl Default for Subcommand {
+    fn default() -> Self {
+        Subcommand::Run {
+            qemu_args: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum CacheCommand {
+    /// List the downloads, harddrives, and QEMU files in the cache with their sizes, whether
+    /// their hashes are recorded for verify, and when they were last used
+    Info,
+    /// Same as clean
+    Clean(CleanOptions),
+}
+
+/// Which QEMU binary to run
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum QemuChoice {
+    /// Installed QEMU if it is suitable, otherwise embedded QEMU, which is built from source
+    /// when there is no embedded binary for the architecture
+    #[default]
+    Auto,
+    System,
+    Embedded,
+}
+
+#[derive(Debug, Default, clap::Args)]
+pub struct CleanOptions {
+    /// Remove everything else too
+    #[arg(long, conflicts_with_all = ["downloads_only", "intermediates"])]
+    pub all: bool,
+    /// Keep partial files
+    #[arg(long, conflicts_with = "intermediates")]
+    pub downloads_only: bool,
+    /// Only remove compressed images and QEMU tarballs that were already decompressed or
+    /// extracted, which --reset downloads again
+    #[arg(long)]
+    pub intermediates: bool,
+    /// Only remove files not modified within this time, like 30d or 12h
+    #[arg(long, value_name = "AGE", value_parser = util::parse_duration)]
+    pub older_than: Option<Duration>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum SnapshotCommand {
+    /// Create a snapshot of the harddrive
+    Create { name: String },
+    /// List the snapshots of the harddrive
+    List,
+    /// Restore the harddrive to a snapshot
+    Restore { name: String },
+    /// Delete a snapshot
+    Delete { name: String },
+}
+
+/// Run Redox OS in QEMU, downloading the image and QEMU when needed
+///
+/// Options can be given before or after the command. Arguments after -- are passed to QEMU
+///
+/// Defaults for the options can be set in config.toml in the rebox config directory, like
+/// ~/.config/rebox/config.toml, with keys named like the options, like memory = "4G",
+/// headless = true, or publish = ["2222:22"], and qemu-args for a list of arguments passed to
+/// QEMU. The options of a profile can be set in a [profile.NAME] section, overriding the
+/// others. Options saved in a profile and given on the command line override them all
+#[derive(Debug, Default, Parser)]
+#[command(version, args_override_self = true)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Subcommand>,
+    /// Command to run, run by default
+    #[arg(skip)]
+    pub subcommand: Subcommand,
+    /// Use a named VM with its own harddrive, saving the other options when it is first used
+    #[arg(long, global = true, value_name = "NAME", value_parser = Profile::new)]
+    pub profile: Option<Profile>,
+    /// Replace the saved options of the profile
+    #[arg(long, global = true)]
+    pub save: bool,
+    /// Directory for downloads, harddrives, and the files of running VMs, instead of rebox in
+    /// the user cache directory
+    #[arg(long, global = true, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Architecture of the Redox image: x86_64, i686, aarch64, riscv64gc
+    #[arg(long, global = true, default_value_t)]
+    pub arch: Arch,
+    /// QEMU machine: q35, pc, virt, or microvm for a minimal x86_64 machine with only virtio
+    /// devices
+    #[arg(long, global = true)]
+    pub machine: Option<Machine>,
+    /// Memory of the guest, like 4G, or in MiB without a unit. By default, a quarter of the
+    /// host memory from 2G to 8G
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_memory)]
+    pub memory: Option<u64>,
+    /// Number of guest CPUs. By default, the number of physical cores of the host, up to 8
+    #[arg(long, global = true, value_name = "N", value_parser = parse_cpus)]
+    pub cpus: Option<usize>,
+    /// QEMU CPU model, like Skylake-Client. By default, host is used with hardware
+    /// acceleration and max without
+    #[arg(long, global = true, value_name = "MODEL")]
+    pub cpu_model: Option<String>,
+    /// Variant of the Redox image: demo, desktop, server, minimal
+    #[arg(long, global = true, default_value_t)]
+    pub variant: Variant,
+    /// Channel to download images from: stable, nightly
+    #[arg(long, global = true, default_value_t)]
+    pub channel: Channel,
+    /// Use images from a Redox release instead of the latest images
+    #[arg(long, global = true, value_name = "VERSION")]
+    pub release: Option<String>,
+    /// Require SHA256SUM to be signed by this OpenPGP public key, checking the SHA256SUM.asc
+    /// signature with gpg
+    #[arg(long, global = true, value_name = "PATH")]
+    pub signing_key: Option<PathBuf>,
+    /// Times a download is retried after a network error, 5 by default, waiting longer
+    /// between each attempt
+    #[arg(long, global = true, value_name = "N")]
+    pub retries: Option<u32>,
+    /// Mirror of static.redox-os.org to download images from, can be repeated. Mirrors are
+    /// tried in order before the official server when a download fails
+    #[arg(long = "mirror", global = true, value_name = "URL", value_parser = parse_mirror)]
+    pub mirrors: Vec<String>,
+    /// Mirror of download.qemu.org to download QEMU source from, can be repeated
+    #[arg(long = "qemu-mirror", global = true, value_name = "URL", value_parser = parse_mirror)]
+    pub qemu_mirrors: Vec<String>,
+    /// Never access the network, failing with a list of what is missing from the cache
+    /// instead of downloading it, and not checking for image updates
+    #[arg(long, global = true)]
+    pub offline: bool,
+    /// Download large files over up to N parallel connections, from 1 to 16, 1 by default
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..=16))]
+    pub connections: Option<u64>,
+    /// Proxy for downloads, like http://proxy:3128 or socks5h://127.0.0.1:1080. By default,
+    /// HTTP_PROXY, HTTPS_PROXY, and ALL_PROXY are used, except for NO_PROXY
+    #[arg(long, global = true, value_name = "URL", value_parser = parse_proxy)]
+    pub proxy: Option<String>,
+    /// Limit downloads to RATE bytes per second in total, like 500K or 5M
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_rate)]
+    pub limit_rate: Option<u64>,
+    /// Download images over BitTorrent with aria2c, falling back to HTTP if there is no
+    /// torrent or it fails
+    #[arg(long, global = true)]
+    pub torrent: bool,
+    /// Print line-delimited JSON events instead of messages and progress bars, for other
+    /// programs to follow what rebox is doing
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Prepare everything needed to run, then print the QEMU command with its environment and
+    /// the files it uses instead of running it
+    #[arg(long, global = true, conflicts_with = "detach")]
+    pub dry_run: bool,
+    /// Print only warnings and errors, without messages or progress bars
+    #[arg(long, global = true, conflicts_with = "json")]
+    pub quiet: bool,
+    /// Replace the harddrive when a newer image is available
+    #[arg(long, global = true)]
+    pub update: bool,
+    /// Recreate the harddrive from the cached image
+    #[arg(long, global = true)]
+    pub reset: bool,
+    /// Remove compressed images and QEMU tarballs once they are decompressed or extracted,
+    /// like cache clean --intermediates. Save it in a profile to always do it
+    #[arg(long, global = true)]
+    pub auto_clean: bool,
+    /// Convert the harddrive to qcow2, which supports snapshots
+    #[arg(long, global = true)]
+    pub qcow2: bool,
+    /// Discard all changes to the harddrive when QEMU exits
+    #[arg(long, global = true)]
+    pub ephemeral: bool,
+    /// Start from a state saved with rebox save instead of booting
+    #[arg(long, global = true, value_name = "NAME")]
+    pub load_state: Option<String>,
+    /// Boot the livedisk ISO instead of the harddrive image
+    #[arg(long, global = true)]
+    pub livedisk: bool,
+    /// With --livedisk, attach a blank harddrive of this size, like 8G
+    #[arg(long, global = true, value_name = "SIZE", value_parser = util::parse_size)]
+    pub blank_disk: Option<u64>,
+    /// Require the QEMU installed on the system. By default, it is used if it is recent
+    /// enough, otherwise the embedded QEMU is used
+    #[arg(long, global = true, overrides_with = "embedded_qemu")]
+    system_qemu: bool,
+    /// Use the QEMU embedded in rebox, even if QEMU is installed
+    #[arg(long, global = true, overrides_with = "system_qemu")]
+    embedded_qemu: bool,
+    /// QEMU binary to run, from --system-qemu and --embedded-qemu
+    #[arg(skip)]
+    pub qemu: QemuChoice,
+    /// Run a specific QEMU release, building it from source if it is not the embedded
+    /// version
+    #[arg(long, global = true, value_name = "VERSION", value_parser = parse_qemu_version)]
+    pub qemu_version: Option<String>,
+    /// Boot with UEFI firmware from the QEMU source instead of the BIOS, keeping its
+    /// variables with the harddrive of the profile
+    #[arg(long, global = true)]
+    pub uefi: bool,
+    /// Boot with UEFI Secure Boot, using the OVMF firmware installed on the system with the
+    /// default keys enrolled. Requires x86_64 and the q35 machine
+    #[arg(long, global = true)]
+    pub secure_boot: bool,
+    /// Accelerator to use: kvm, hvf, whpx, tcg. By default, the best available one is used
+    #[arg(long, global = true)]
+    pub accel: Option<Accel>,
+    /// Do not use KVM, even if it is available
+    #[arg(long, global = true)]
+    pub no_kvm: bool,
+    /// Expose virtualization to the guest, requires KVM with nested virtualization enabled
+    /// on the host
+    #[arg(long, global = true)]
+    pub nested: bool,
+    /// Connect a serial port to stdio, file:<PATH>, pty, tcp:<PORT> on localhost, or none.
+    /// Can be given multiple times for more serial ports. By default, the first serial port
+    /// uses stdio
+    #[arg(long, global = true)]
+    pub serial: Vec<Serial>,
+    /// Use the terminal as an interactive console for the first serial port and the QEMU
+    /// monitor. Ctrl-a x quits
+    #[arg(long, global = true)]
+    pub console: bool,
+    /// Run the VM in the background, with the serial console on a socket and logged, and
+    /// return once it is started
+    #[arg(long, global = true)]
+    pub detach: bool,
+    /// Add a GDB stub on localhost, port 1234 by default, and wait for a debugger before
+    /// starting the guest
+    #[arg(long, global = true, value_name = "PORT", num_args = 0..=1, default_missing_value = "1234")]
+    pub gdb: Option<u16>,
+    /// Also write the serial console on stdio to a log file, by default a new one in the logs
+    /// directory of the profile
+    #[arg(long, global = true, value_name = "PATH", num_args = 0..=1)]
+    pub serial_log: Option<Option<PathBuf>>,
+    /// Add a virtio-serial channel for an agent in the guest, which rebox agent connects to
+    #[arg(long, global = true)]
+    pub agent: bool,
+    /// Add a virtio-balloon device, so the memory of the guest can be changed while it runs
+    /// with rebox balloon
+    #[arg(long, global = true)]
+    pub balloon: bool,
+    /// Add a TPM 2.0 emulated by swtpm, which must be installed, with its state kept with the
+    /// harddrive of the profile
+    #[arg(long, global = true)]
+    pub tpm: bool,
+    /// Real time clock: base=utc|localtime,clock=host|vm. By default, the clock starts in UTC
+    /// and follows the host time
+    #[arg(long, global = true, value_name = "OPTIONS", default_value_t)]
+    pub rtc: Rtc,
+    /// Host audio backend: pipewire, pulse, alsa, sdl, coreaudio, or none to discard audio
+    #[arg(long, global = true, value_name = "BACKEND")]
+    pub audio: Option<Audio>,
+    /// Do not add an audio device, which is not needed on headless machines
+    #[arg(long, global = true)]
+    pub no_audio: bool,
+    /// Network card: virtio-net-pci, e1000, rtl8139. By default, e1000 is used, or
+    /// virtio-net-pci with microvm
+    #[arg(long, global = true, value_name = "MODEL")]
+    pub nic_model: Option<NicModel>,
+    /// MAC address of the network card. By default, an address is derived from the profile
+    /// name, so it stays the same
+    #[arg(long, global = true, value_name = "ADDRESS")]
+    pub mac: Option<MacAddr>,
+    /// Connect the network card to user mode networking with user, an existing tap device
+    /// with tap[,ifname=<NAME>], tap0 by default, or a bridge with tap,bridge=<BRIDGE> using
+    /// qemu-bridge-helper
+    #[arg(long = "net", global = true, value_name = "NETWORK", default_value_t)]
+    pub network: Network,
+    /// Add a network card connected to a network like with --net, or
+    /// socket,<listen|connect|mcast>=<ADDR> to connect VMs together, with optional
+    /// ,model=<MODEL> and ,mac=<ADDRESS>. Can be given multiple times, instead of --net,
+    /// --nic-model, and --mac. The options below apply to the first card using user mode
+    /// networking
+    #[arg(long = "nic", global = true, value_name = "NETWORK")]
+    pub nics: Vec<Nic>,
+    /// Addressing of user mode networking, like net=10.5.0.0/24,host=10.5.0.2,dns=10.5.0.3,
+    /// with the keys net, host, dhcpstart, dns, dnssearch, domainname, hostname, and tftp
+    #[arg(long, global = true, value_name = "OPTIONS")]
+    pub net_opts: Option<UserOptions>,
+    /// Cut the guest off from the network with user mode networking, keeping forwarded ports
+    /// and the services of QEMU
+    #[arg(long, global = true)]
+    pub net_restrict: bool,
+    /// Forward a port on localhost to a port of the guest, like 2222:22, adding /udp for UDP.
+    /// Can be given multiple times
+    #[arg(long, global = true, value_name = "HOST:GUEST")]
+    pub publish: Vec<Publish>,
+    /// Share a host directory over SMB with user mode networking, at \\10.0.2.4\qemu in the
+    /// guest. Requires Samba
+    #[arg(long, global = true, value_name = "DIR")]
+    pub smb: Option<PathBuf>,
+    /// Capture the traffic of the first network card to a pcap file. The previous captures
+    /// are kept as FILE.1 to FILE.3
+    #[arg(long, global = true, value_name = "FILE")]
+    pub pcap: Option<PathBuf>,
+    /// Start a new capture when it grows over SIZE, 100M by default
+    #[arg(long, global = true, value_name = "SIZE", value_parser = util::parse_size)]
+    pub pcap_max_size: Option<u64>,
+    /// Do not add the virtio-rng device, which provides entropy from the host to the guest
+    #[arg(long, global = true)]
+    pub no_rng: bool,
+    /// Storage controller of the harddrive: nvme, virtio, ahci, ide. By default, the
+    /// controller built into the machine is used, or nvme with virt and virtio with microvm
+    #[arg(long, global = true, value_name = "INTERFACE")]
+    pub disk_if: Option<DiskInterface>,
+    /// Window used to show the guest: gtk, sdl, cocoa, dbus, none. If QEMU does not support
+    /// it, another one is used
+    #[arg(long, global = true)]
+    pub display: Option<Display>,
+    /// Start the window in fullscreen
+    #[arg(long, global = true)]
+    pub fullscreen: bool,
+    /// Size of the window, like 1280x800, which is set as the preferred resolution of the
+    /// guest, as the window follows it
+    #[arg(long, global = true, value_name = "WIDTHxHEIGHT")]
+    pub window_size: Option<Resolution>,
+    /// Scale the guest display to the size of the window
+    #[arg(long, global = true)]
+    pub zoom_to_fit: bool,
+    /// Scale the window by N, from 1 to 4, for HiDPI monitors
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=4))]
+    pub scale: Option<u32>,
+    /// Do not open a window or add an audio device, for servers and CI where only the serial
+    /// console is used
+    #[arg(long, global = true)]
+    pub headless: bool,
+    /// Show the guest over VNC instead of a window, on display :0 by default, which listens
+    /// on port 5900
+    #[arg(long, global = true, value_name = ":DISPLAY", num_args = 0..=1, default_missing_value = ":0", value_parser = vnc::parse_display)]
+    pub vnc: Option<u16>,
+    /// Address the VNC server listens on, 127.0.0.1 by default
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub vnc_listen: Option<String>,
+    /// Require a generated password to connect to the VNC server. Requires QEMU 7.0 or newer
+    #[arg(long, global = true)]
+    pub vnc_password: bool,
+    /// Graphics device: std for the VGA device of the machine or a framebuffer, virtio, or
+    /// virgl for virtio-gpu with OpenGL
+    #[arg(long, global = true, default_value_t)]
+    pub gpu: Gpu,
+    /// VGA device of x86 machines with --gpu std: std, cirrus, vmware, qxl
+    #[arg(long, global = true)]
+    pub vga: Option<Vga>,
+    /// Preferred display resolution, like 1920x1080, reported to the guest by the std and qxl
+    /// VGA devices and virtio-gpu
+    #[arg(long, global = true, value_name = "WIDTHxHEIGHT")]
+    pub resolution: Option<Resolution>,
+    /// Share a host directory with the guest, which mounts it by TAG. Can be given multiple
+    /// times. Uses virtio-9p unless --virtiofs is given
+    #[arg(long = "share", global = true, value_name = "PATH:TAG")]
+    pub shares: Vec<Share>,
+    /// Share directories with virtiofsd, which must be installed
+    #[arg(long, global = true)]
+    pub virtiofs: bool,
+    /// Add a USB tablet, so the pointer moves between the host and guest without grabbing
+    /// the mouse
+    #[arg(long, global = true)]
+    pub tablet: bool,
+    /// Pass through a USB device of the host, like 0781:5567. Can be given multiple times
+    #[arg(long = "usb-device", global = true, value_name = "VENDORID:PRODUCTID")]
+    pub usb_devices: Vec<UsbDevice>,
+    /// Use a local raw, qcow2, or zstd compressed harddrive image
+    #[arg(long, global = true, value_name = "PATH")]
+    pub image: Option<PathBuf>,
+    /// Download the harddrive image from a custom URL
+    #[arg(long, global = true, value_name = "URL")]
+    pub image_url: Option<String>,
+    /// Expected SHA-256 hash of the image at --image-url
+    #[arg(long, global = true, value_name = "HASH")]
+    pub image_sha256: Option<String>,
+    /// Arguments passed to QEMU
+    #[arg(last = true, value_name = "QEMU ARGS")]
+    pub qemu_args: Vec<String>,
+}
+
+impl Args {
+    pub fn parse() -> Result<Self, String> {
+        let cli: Vec<String> = env::args().skip(1).collect();
+        let mut args = Self::parse_from(iter::once("rebox".to_string()).chain(cli.clone()));
+
+        // Defaults from the configuration file are checked on their own, so errors in them are
+        // reported with its path
+        let config = config::load_args(args.profile.as_ref())?;
+        if !config.is_empty() {
+            let config_args = iter::once("rebox".to_string()).chain(config.iter().cloned());
+            if let Err(err) = Self::try_parse_from(config_args) {
+                eprintln!("in {:?}:", config::path().unwrap_or_default());
+                err.exit();
+            }
+        }
+
+        let run = matches!(args.command, None | Some(Subcommand::Run { .. }));
+        let subcommand = subcommand_index(&cli);
+        let mut saved = Vec::new();
+        let mut save = false;
+        if let Some(profile) = &args.profile {
+            let profile_name = &profile.name;
+            let loaded = profile
+                .load_args()
+                .map_err(|err| format!("failed to load profile {profile_name:?}: {err}"))?;
+            match loaded {
+                Some(loaded) if !args.save => saved = loaded,
+                // Saved once the options are checked and the output is configured
+                _ => save = run,
+            }
+        }
+        if !config.is_empty() || !saved.is_empty() {
+            // The configured defaults come first, then the options saved in the profile, so
+            // they are overridden by the command line
+            let profile_name = args.profile.as_ref().map(|profile| profile.name.clone());
+            let cli = with_saved(&cli, subcommand, &[&config, &saved], run);
+            args = Self::try_parse_from(cli).unwrap_or_else(|err| {
+                if let Some(profile_name) = profile_name {
+                    eprintln!("in the options saved in profile {profile_name:?}:");
+                }
+                err.exit()
+            });
+        }
+
+        args.subcommand = args.command.take().unwrap_or_default();
+        if let Subcommand::Run { qemu_args } = &mut args.subcommand {
+            args.qemu_args.append(qemu_args);
+        }
+        args.qemu = if args.system_qemu {
+            QemuChoice::System
+        } else if args.embedded_qemu {
+            QemuChoice::Embedded
+        } else {
+            QemuChoice::Auto
+        };
+
+        if args.save && args.profile.is_none() {
+            return Err("--save requires --profile".to_string());
+        }
+        if args.image_sha256.is_some() && args.image_url.is_none() {
+            return Err("--image-sha256 requires --image-url".to_string());
+        }
+        if args.offline && args.update {
+            return Err("--offline cannot be used with --update".to_string());
+        }
+        if args.torrent && (args.offline || args.proxy.is_some()) {
+            return Err("--torrent cannot be used with --offline or --proxy".to_string());
+        }
+        if args.channel != Channel::Stable && args.release.is_some() {
+            return Err("--release cannot be used with --channel".to_string());
+        }
+        if args.uefi && args.machine == Some(Machine::Microvm) {
+            return Err("--uefi cannot be used with --machine microvm".to_string());
+        }
+        if args.secure_boot
+            && (args.arch != Arch::X86_64 || args.machine.is_some_and(|m| m != Machine::Q35))
+        {
+            return Err("--secure-boot requires --arch x86_64 and --machine q35".to_string());
+        }
+        let stdio = args.serial.iter().filter(|s| **s == Serial::Stdio).count();
+        if stdio > 1 {
+            return Err("--serial stdio can only be used once".to_string());
+        }
+        if args.serial.len() > 1 && args.serial.contains(&Serial::None) {
+            return Err("--serial none cannot be used with other serial ports".to_string());
+        }
+        if args.detach && (args.console || stdio > 0) {
+            return Err("--detach cannot be used with --console or --serial stdio".to_string());
+        }
+        if args.detach && args.pcap.is_some() {
+            // Captures are rotated by rebox while the VM runs
+            return Err("--detach cannot be used with --pcap".to_string());
+        }
+        if args.load_state.is_some() && args.ephemeral {
+            // The temporary overlay does not contain the snapshots of the harddrive
+            return Err("--load-state cannot be used with --ephemeral".to_string());
+        }
+        if args.serial_log.is_some() && !args.console && !args.serial.is_empty() && stdio == 0 {
+            return Err("--serial-log requires a serial port on stdio".to_string());
+        }
+        if args.console && (stdio > 0 || args.serial.contains(&Serial::None)) {
+            return Err("--console cannot be used with --serial stdio or none".to_string());
+        }
+        if args.virtiofs && args.shares.is_empty() {
+            return Err("--virtiofs requires --share".to_string());
+        }
+        let single_nic = args.network != Network::default() || args.nic_model.is_some();
+        if !args.nics.is_empty() && (single_nic || args.mac.is_some()) {
+            return Err("--nic cannot be used with --net, --nic-model, or --mac".to_string());
+        }
+        let user_net = if args.nics.is_empty() {
+            args.network == Network::User
+        } else {
+            args.nics.iter().any(|nic| nic.network == Network::User)
+        };
+        if !args.publish.is_empty() && !user_net {
+            return Err("--publish requires user mode networking".to_string());
+        }
+        if args.net_opts.is_some() && !user_net {
+            return Err("--net-opts requires user mode networking".to_string());
+        }
+        if args.net_restrict && !user_net {
+            return Err("--net-restrict requires user mode networking".to_string());
+        }
+        if args.smb.is_some() && !user_net {
+            return Err("--smb requires user mode networking".to_string());
+        }
+        if args.pcap_max_size.is_some() && args.pcap.is_none() {
+            return Err("--pcap-max-size requires --pcap".to_string());
+        }
+        if args.blank_disk.is_some() && !args.livedisk {
+            return Err("--blank-disk requires --livedisk".to_string());
+        }
+        if args.livedisk && args.machine == Some(Machine::Microvm) {
+            return Err("--livedisk cannot be used with --machine microvm".to_string());
+        }
+        if args.livedisk && (args.image.is_some() || args.image_url.is_some()) {
+            return Err("--livedisk cannot be used with --image or --image-url".to_string());
+        }
+        if args.qemu_version.is_some() && args.qemu == QemuChoice::System {
+            return Err("--qemu-version cannot be used with --system-qemu".to_string());
+        }
+        if args.no_kvm && args.accel == Some(Accel::Kvm) {
+            return Err("--no-kvm cannot be used with --accel kvm".to_string());
+        }
+        if args.display.is_some() && (args.headless || args.vnc.is_some()) {
+            return Err("--display cannot be used with --headless or --vnc".to_string());
+        }
+        let window = args.fullscreen
+            || args.window_size.is_some()
+            || args.zoom_to_fit
+            || args.scale.is_some();
+        if window && (args.headless || args.vnc.is_some()) {
+            return Err(
+                "--fullscreen, --window-size, --zoom-to-fit, and --scale cannot be \
+                 used with --headless or --vnc"
+                    .to_string(),
+            );
+        }
+        if args.window_size.is_some() && args.resolution.is_some() {
+            return Err("--window-size cannot be used with --resolution".to_string());
+        }
+        if (args.vnc_listen.is_some() || args.vnc_password) && args.vnc.is_none() {
+            return Err("--vnc-listen and --vnc-password require --vnc".to_string());
+        }
+        if args.headless && args.audio.is_some() {
+            return Err("--headless cannot be used with --audio".to_string());
+        }
+        if args.no_audio && args.audio.is_some() {
+            return Err("--no-audio cannot be used with --audio".to_string());
+        }
+        if args.vga.is_some() && args.gpu != Gpu::Std {
+            return Err("--vga cannot be used with --gpu virtio or virgl".to_string());
+        }
+        if args.vga.is_some() && !matches!(args.arch, Arch::X86_64 | Arch::I686) {
+            return Err(format!("--vga cannot be used with {}", args.arch));
+        }
+        if args.image.is_some() && args.image_url.is_some() {
+            return Err("--image cannot be used with --image-url".to_string());
+        }
+
+        output::configure(if args.json {
+            output::Mode::Json
+        } else if args.quiet {
+            output::Mode::Quiet
+        } else {
+            output::Mode::Human
+        });
+        if let Some(profile) = args.profile.as_ref().filter(|_| save) {
+            let profile_name = &profile.name;
+            output::status!("saving profile {profile_name:?}");
+            profile
+                .save_args(&settings(&cli, subcommand))
+                .map_err(|err| format!("failed to save profile {profile_name:?}: {err}"))?;
+        }
+        Ok(args)
+    }
+}
+
+/// Parse a size, in MiB if there is no unit like QEMU
+fn parse_mib(s: &str) -> Result<u64, String> {
+    if s.chars().all(|c| c.is_ascii_digit()) {
+        util::parse_size(&format!("{s}M"))
+    } else {
+        util::parse_size(s)
+    }
+}
+
+/// Parse a memory size, in MiB if there is no unit like QEMU, checking it fits in the host
+fn parse_memory(s: &str) -> Result<u64, String> {
+    let memory = parse_mib(s)?;
+    if memory < 1 << 20 || memory % (1 << 20) != 0 {
+        return Err(format!(
+            "invalid memory {s:?}, must be a whole number of MiB"
+        ));
+    }
+    if let Some(total) = host::total_memory() {
+        if memory > total {
+            return Err(format!(
+                "memory {s:?} is more than the {} of the host",
+                util::format_size(total)
+            ));
+        }
+    }
+    if let Some(available) = host::available_memory() {
+        if memory > available {
+            log::warn!(
+                "memory {s:?} is more than the {} available on the host, which may cause swapping",
+                util::format_size(available)
+            );
+        }
+    }
+    Ok(memory)
+}
+
+fn parse_cpus(s: &str) -> Result<usize, String> {
+    let cpus: usize = s
+        .parse()
+        .map_err(|err| format!("invalid CPU count {s:?}: {err}"))?;
+    if cpus == 0 {
+        return Err("CPU count must be at least 1".to_string());
+    }
+    let logical = host::logical_cpus();
+    if cpus > logical {
+        log::warn!("{cpus} CPUs is more than the {logical} of the host, which will be slow");
+    }
+    Ok(cpus)
+}
+
+fn parse_mirror(url: &str) -> Result<String, String> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        Ok(url.trim_end_matches('/').to_string())
+    } else {
+        Err(format!(
+            "invalid mirror {url:?}, expected an http or https URL"
+        ))
+    }
+}
+
+fn parse_proxy(url: &str) -> Result<String, String> {
+    let schemes = ["http://", "https://", "socks5://", "socks5h://"];
+    if schemes.iter().any(|scheme| url.starts_with(scheme)) {
+        Ok(url.to_string())
+    } else {
+        Err(format!(
+            "invalid proxy {url:?}, expected an http, https, socks5, or socks5h URL"
+        ))
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    match util::parse_size(s)? {
+        0 => Err("invalid rate limit, expected more than 0".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+fn parse_qemu_version(version: &str) -> Result<String, String> {
+    qemu::release(version)?;
+    Ok(version.to_string())
+}
+
+/// Names of VMs are checked like profiles, as they are used in file names
+fn parse_name(name: &str) -> Result<String, String> {
+    Profile::new(name)?;
+    Ok(name.to_string())
+}
+
+/// Print the completion script for shell
+pub fn completions(shell: Shell) {
+    clap_complete::generate(shell, &mut Args::command(), "rebox", &mut io::stdout());
+}
+
+/// Position of the command in the arguments, after the options given before it
+fn subcommand_index(cli: &[String]) -> Option<usize> {
+    let mut command = Args::command();
+    command.build();
+    let mut i = 0;
+    while let Some(arg) = cli.get(i) {
+        if arg == "--" {
+            return None;
+        }
+        if let Some(long) = arg.strip_prefix("--") {
+            // The value of an option is given as the next argument unless it is optional
+            let takes_value = command.get_arguments().any(|option| {
+                option.get_long() == Some(long)
+                    && option
+                        .get_num_args()
+                        .is_some_and(|num| num.min_values() > 0)
+            });
+            if takes_value {
+                i += 1;
+            }
+        } else if !arg.starts_with('-') {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Split arguments at the first --, returning the arguments before and after it
+fn split_trailing(args: &[String]) -> (&[String], &[String]) {
+    match args.iter().position(|arg| arg == "--") {
+        Some(i) => (&args[..i], &args[i + 1..]),
+        None => (args, &[]),
+    }
+}
+
+/// Arguments with saved settings inserted after the command, in order, so they are overridden
+/// by the options given with it. Saved QEMU arguments only apply to runs
+fn with_saved(
+    cli: &[String],
+    subcommand: Option<usize>,
+    saved: &[&[String]],
+    run: bool,
+) -> Vec<String> {
+    let (command, options) = cli.split_at(subcommand.map_or(0, |i| i + 1));
+    let (options, cli_trailing) = split_trailing(options);
+    let mut args = vec!["rebox".to_string()];
+    args.extend_from_slice(command);
+    let mut trailing = Vec::new();
+    for saved in saved {
+        let (saved, saved_qemu_args) = split_trailing(saved);
+        args.extend_from_slice(saved);
+        if run {
+            trailing.extend_from_slice(saved_qemu_args);
+        }
+    }
+    args.extend_from_slice(options);
+    trailing.extend_from_slice(cli_trailing);
+    if !trailing.is_empty() {
+        args.push("--".to_string());
+        args.extend(trailing);
+    }
+    args
+}
+
+/// Flags that only apply to the run they are given for, which are not saved in a profile
+const SINGLE_RUN_FLAGS: &[&str] = &[
+    "--save",
+    "--update",
+    "--reset",
+    "--offline",
+    "--dry-run",
+    "--json",
+    "--quiet",
+    "--detach",
+];
+
+/// Options with a value that only apply to the run they are given for
+const SINGLE_RUN_OPTIONS: &[&str] = &["--profile", "--cache-dir", "--load-state", "--gdb"];
+
+/// Arguments that are saved in a profile, excluding the command and options that only apply
+/// to one run
+fn settings(cli: &[String], subcommand: Option<usize>) -> Vec<String> {
+    let mut settings = Vec::new();
+    let mut iter = cli
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Some(*i) != subcommand)
+        .map(|(_, arg)| arg)
+        .peekable();
+    while let Some(arg) = iter.next() {
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        if arg == "--" {
+            settings.push(arg.clone());
+            settings.extend(iter.cloned());
+            break;
+        } else if SINGLE_RUN_OPTIONS.contains(&name) {
+            // The value is the next argument, unless it is optional like the port of --gdb
+            if name == arg && iter.peek().is_some_and(|value| !value.starts_with('-')) {
+                iter.next();
+            }
+        } else if !SINGLE_RUN_FLAGS.contains(&arg.as_str()) {
+            settings.push(arg.clone());
+        }
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn with_saved_before_cli_options() {
+        let cli = strings(&["run", "--memory", "8G"]);
+        let config = strings(&["--cpus", "2"]);
+        let saved = strings(&["--memory", "4G"]);
+        assert_eq!(
+            with_saved(&cli, Some(0), &[&config, &saved], true),
+            strings(&["rebox", "run", "--cpus", "2", "--memory", "4G", "--memory", "8G"])
+        );
+    }
+
+    #[test]
+    fn with_saved_qemu_args_only_for_runs() {
+        let cli = strings(&["--", "-s"]);
+        let saved = strings(&["--memory", "4G", "--", "-S"]);
+        assert_eq!(
+            with_saved(&cli, None, &[&saved], true),
+            strings(&["rebox", "--memory", "4G", "--", "-S", "-s"])
+        );
+        let cli = strings(&["ssh"]);
+        assert_eq!(
+            with_saved(&cli, Some(0), &[&saved], false),
+            strings(&["rebox", "ssh", "--memory", "4G"])
+        );
+    }
+
+    #[test]
+    fn settings_skip_single_run_options() {
+        let cli = strings(&[
+            "--profile",
+            "demo",
+            "run",
+            "--memory",
+            "4G",
+            "--save",
+            "--cache-dir=/tmp/cache",
+            "--load-state",
+            "booted",
+            "--gdb",
+            "--json",
+            "--cpus",
+            "2",
+            "--gdb=4321",
+            "--dry-run",
+            "--",
+            "-s",
+        ]);
+        let subcommand = subcommand_index(&cli);
+        let args = Args::try_parse_from(with_saved(&cli, subcommand, &[], true)).unwrap();
+        assert_eq!(args.load_state.as_deref(), Some("booted"));
+        assert_eq!(args.gdb, Some(4321));
+        assert_eq!(
+            settings(&cli, subcommand),
+            strings(&["--memory", "4G", "--cpus", "2", "--", "-s"])
+        );
+    }
+
+    #[test]
+    fn settings_skip_optional_value() {
+        let cli = strings(&["--gdb", "1235", "--ephemeral", "--gdb", "--detach"]);
+        assert_eq!(settings(&cli, None), strings(&["--ephemeral"]));
+    }
+}
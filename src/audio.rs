@@ -0,0 +1,68 @@
+use std::{fmt, str::FromStr};
+
+/// Host audio backend connected to the HDA output
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Audio {
+    Pipewire,
+    Pulse,
+    Alsa,
+    Sdl,
+    Coreaudio,
+    /// Audio is emulated but discarded
+    None,
+}
+
+impl Audio {
+    pub const ALL: [Audio; 6] = [
+        Audio::Pipewire,
+        Audio::Pulse,
+        Audio::Alsa,
+        Audio::Sdl,
+        Audio::Coreaudio,
+        Audio::None,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Audio::Pipewire => "pipewire",
+            Audio::Pulse => "pulse",
+            Audio::Alsa => "alsa",
+            Audio::Sdl => "sdl",
+            Audio::Coreaudio => "coreaudio",
+            Audio::None => "none",
+        }
+    }
+
+    /// Name of the QEMU -audiodev driver
+    pub fn driver(&self) -> &'static str {
+        match self {
+            Audio::Pulse => "pa",
+            _ => self.name(),
+        }
+    }
+}
+
+impl fmt::Display for Audio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Audio {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pipewire" => Ok(Audio::Pipewire),
+            "pulse" => Ok(Audio::Pulse),
+            "alsa" => Ok(Audio::Alsa),
+            "sdl" => Ok(Audio::Sdl),
+            "coreaudio" => Ok(Audio::Coreaudio),
+            "none" => Ok(Audio::None),
+            _ => Err(format!(
+                "unknown audio backend {s:?}, expected one of {}",
+                Audio::ALL.map(|audio| audio.name()).join(", ")
+            )),
+        }
+    }
+}
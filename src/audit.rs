@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{output, qemu, store, util};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Status {
+    Intact,
+    Missing,
+    Corrupted,
+    /// Harddrive written to since it was created
+    Modified,
+}
+
+fn files(dir: &Path, skip: &[PathBuf], files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if skip.contains(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            self::files(&path, skip, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn check(path: &Path, sha256: &str) -> io::Result<Status> {
+    if !path.is_file() {
+        return Ok(Status::Missing);
+    }
+    let mut file = fs::File::open(path)?;
+    if util::sha256(&mut file)? == sha256 {
+        Ok(Status::Intact)
+    } else {
+        Ok(Status::Corrupted)
+    }
+}
+
+fn report(status: Status, path: &Path) {
+    let label = match status {
+        Status::Intact => "intact",
+        Status::Missing => "missing",
+        Status::Corrupted => "CORRUPTED",
+        Status::Modified => "modified",
+    };
+    output::status!("{label:>9} {}", path.display());
+}
+
+/// Check the hashes of all cached files, optionally repairing corrupted files
+pub fn run(cache_dir: &Path, repair: bool) -> Result<(), Box<dyn Error>> {
+    let mut corrupted = 0;
+    // Downloads in the store are named after their hash
+    let mut stored = Vec::new();
+    match fs::read_dir(store::dir(cache_dir)) {
+        Ok(entries) => {
+            for entry in entries {
+                stored.push(entry?.path());
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    stored.sort();
+    for path in stored.iter().filter(|path| path.extension().is_none()) {
+        let sha256 = path.file_name().unwrap_or_default().to_string_lossy();
+        let status = check(path, &sha256)?;
+        report(status, path);
+        if status == Status::Corrupted {
+            if repair {
+                // Downloaded again when it is next needed
+                fs::remove_file(path)?;
+                output::status!("removed {}, it will be downloaded again", path.display());
+            } else {
+                corrupted += 1;
+            }
+        }
+    }
+
+    for release in qemu::RELEASES {
+        // The QEMU source is only needed until it is extracted, so it may be missing. Tarballs
+        // downloaded before the store was used are checked where they are
+        let sha256 = release.sha256;
+        let qemu_tar_xz = release.legacy_tarball_path(cache_dir);
+        if !qemu_tar_xz.exists() {
+            continue;
+        }
+        let status = check(&qemu_tar_xz, sha256)?;
+        report(status, &qemu_tar_xz);
+        if status == Status::Corrupted {
+            if repair {
+                util::sha256_or_download(&release.url(), sha256, &qemu_tar_xz)?;
+            } else {
+                corrupted += 1;
+            }
+        }
+    }
+
+    for (name, binary) in qemu::embedded_binaries() {
+        let qemu_system = cache_dir.join(name);
+        if !qemu_system.exists() {
+            // Only extracted when used
+            continue;
+        }
+        let status = check(&qemu_system, &util::sha256(&mut &binary[..])?)?;
+        report(status, &qemu_system);
+        if status == Status::Corrupted {
+            if repair {
+                // Extracted again on the next run
+                fs::remove_file(&qemu_system)?;
+                output::status!(
+                    "removed {}, it will be extracted again",
+                    qemu_system.display()
+                );
+            } else {
+                corrupted += 1;
+            }
+        }
+    }
+
+    let mut paths = Vec::new();
+    // QEMU source and build directories are checked when they are used, and the store above
+    let skip = [
+        cache_dir.join("qemu"),
+        cache_dir.join("qemu-build"),
+        store::dir(cache_dir),
+    ];
+    files(cache_dir, &skip, &mut paths)?;
+
+    // Compressed images are found by name, as they may be shared by harddrives in profiles
+    let mut by_name: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for path in &paths {
+        if let Some(name) = path.file_name() {
+            by_name
+                .entry(name.to_string_lossy().into_owned())
+                .or_default()
+                .push(path.clone());
+        }
+    }
+
+    // Records of the images harddrives were created from, in SHA256SUM format. Records in the
+    // cache directory itself are for the QEMU binaries, which were checked above
+    for source_path in paths.iter().filter(|path| {
+        path.extension().is_some_and(|ext| ext == "sha256") && path.parent() != Some(cache_dir)
+    }) {
+        let source = fs::read_to_string(source_path)?;
+        let mut lines = source.lines().filter_map(|line| line.split_once("  "));
+
+        if let Some((image_sha256, image_name)) = lines.next() {
+            let image_paths = by_name.get(image_name).cloned().unwrap_or_default();
+            let is_stored = store::path(cache_dir, image_sha256).is_file();
+            if image_paths.is_empty() && !is_stored {
+                // Compressed images may have been removed after decompression
+                report(Status::Missing, &source_path.with_file_name(image_name));
+            }
+            for image_path in image_paths {
+                let status = check(&image_path, image_sha256)?;
+                report(status, &image_path);
+                if status == Status::Corrupted {
+                    if repair {
+                        // Downloaded again when it is next needed
+                        fs::remove_file(&image_path)?;
+                        output::status!(
+                            "removed {}, it will be downloaded again",
+                            image_path.display()
+                        );
+                    } else {
+                        corrupted += 1;
+                    }
+                }
+            }
+        }
+
+        if let Some((hd_sha256, hd_name)) = lines.next() {
+            let hd_path = source_path.with_file_name(hd_name);
+            let status = match check(&hd_path, hd_sha256)? {
+                // Harddrives are written to by the guest, so a different hash is expected
+                Status::Corrupted => Status::Modified,
+                status => status,
+            };
+            report(status, &hd_path);
+        }
+    }
+
+    if corrupted > 0 {
+        return Err(
+            format!("{corrupted} corrupted files found, run with --repair to fix them").into(),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use std::{error::Error, fs, path::Path, process::Command};
+
+use crate::{arch::Arch, image::Variant, output, profile::Profile, util};
+
+/// Options that select the harddrive image, replaced when registering a build
+const IMAGE_OPTIONS: [(&str, bool); 8] = [
+    ("--arch", true),
+    ("--variant", true),
+    ("--channel", true),
+    ("--release", true),
+    ("--image", true),
+    ("--image-url", true),
+    ("--image-sha256", true),
+    ("--livedisk", false),
+];
+
+/// Build a harddrive image from a Redox source tree and use it for a profile
+pub fn run(
+    source_dir: &Path,
+    arch: Arch,
+    variant: Variant,
+    profile: &Profile,
+) -> Result<(), Box<dyn Error>> {
+    let source_dir = fs::canonicalize(source_dir)
+        .map_err(|err| format!("failed to find Redox source tree {source_dir:?}: {err}"))?;
+    if !source_dir.join("mk").join("config.mk").is_file() {
+        return Err(format!("{source_dir:?} is not a Redox source tree").into());
+    }
+
+    let make = util::which("make").ok_or("make not found, please install it")?;
+    let mut command = Command::new(make);
+    command
+        .current_dir(&source_dir)
+        .arg("all")
+        .arg(format!("ARCH={arch}"))
+        .arg(format!("CONFIG_NAME={variant}"));
+    output::status!("running {:?}", command);
+    let status = command.status()?;
+    if !status.success() {
+        return Err(format!("Redox build failed with {status}").into());
+    }
+
+    let hd_path = source_dir
+        .join("build")
+        .join(arch.name())
+        .join(variant.name())
+        .join("harddrive.img");
+    if !hd_path.is_file() {
+        return Err(format!("Redox build did not produce {hd_path:?}").into());
+    }
+
+    // The image is used in place, so rebuilding updates the profile. The architecture and
+    // variant are saved so they are used again when rebuilding
+    let mut settings = vec![
+        "--arch".to_string(),
+        arch.to_string(),
+        "--variant".to_string(),
+        variant.to_string(),
+        "--image".to_string(),
+        hd_path.display().to_string(),
+    ];
+    let saved = profile.load_args()?.unwrap_or_default();
+    let mut iter = saved.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            settings.push(arg);
+            settings.extend(iter);
+            break;
+        }
+        match IMAGE_OPTIONS.iter().find(|(option, _)| *option == arg) {
+            Some((_, true)) => {
+                iter.next();
+            }
+            Some((_, false)) => {}
+            None => settings.push(arg),
+        }
+    }
+    profile.save_args(&settings)?;
+
+    let name = &profile.name;
+    output::status!("built {hd_path:?}, run rebox --profile {name} to use it");
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::output;
+use crate::profile::Profile;
+
+/// Package the saved settings and harddrive of a profile into a tar.zst archive
+pub fn export(profile: &Profile, cache_dir: &Path, path: &Path) -> Result<(), Box<dyn Error>> {
+    let name = &profile.name;
+    let args_path = profile.args_path()?;
+    if !args_path.is_file() {
+        return Err(format!("profile {name:?} has no saved settings").into());
+    }
+    let hd_dir = profile.cache_dir(cache_dir);
+    if !hd_dir.is_dir() {
+        return Err(format!(
+            "profile {name:?} has no harddrive, run rebox --profile {name} to create it"
+        )
+        .into());
+    }
+
+    output::status!("exporting profile {name:?} to {path:?}");
+    let partial = path.with_extension("partial");
+    let file = fs::File::create(&partial)?;
+    let mut tar = tar::Builder::new(zstd::Encoder::new(file, 0)?);
+    // Entries are stored under the profile name, which is used by default when importing
+    tar.append_path_with_name(&args_path, format!("{name}/profile.args"))?;
+    tar.append_dir_all(format!("{name}/disk"), &hd_dir)?;
+    let file = tar.into_inner()?.finish()?;
+    file.sync_all()?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Create a profile from an archive made by export, named after the exported profile by default
+pub fn import(
+    path: &Path,
+    cache_dir: &Path,
+    profile: Option<&Profile>,
+) -> Result<(), Box<dyn Error>> {
+    let file = fs::File::open(path)?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(file)?);
+    // Unpacked inside the cache, so the harddrive can be moved into place without copying
+    let tmp = tempfile::tempdir_in(cache_dir)?;
+    output::status!("importing {path:?}");
+    archive.unpack(tmp.path())?;
+
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(tmp.path())? {
+        dirs.push(entry?.path());
+    }
+    let bundle_dir: PathBuf = match dirs.as_slice() {
+        [dir] if dir.join("profile.args").is_file() && dir.join("disk").is_dir() => dir.clone(),
+        _ => return Err(format!("{path:?} is not an exported profile").into()),
+    };
+
+    let profile = match profile {
+        Some(profile) => profile.clone(),
+        None => Profile::new(&bundle_dir.file_name().unwrap_or_default().to_string_lossy())?,
+    };
+    let name = &profile.name;
+    let hd_dir = profile.cache_dir(cache_dir);
+    if profile.args_path()?.exists() || hd_dir.exists() {
+        return Err(format!(
+            "profile {name:?} already exists, use --profile to import with another name"
+        )
+        .into());
+    }
+
+    let args = fs::read_to_string(bundle_dir.join("profile.args"))?;
+    let args: Vec<String> = args.lines().map(str::to_string).collect();
+    if let Some(parent) = hd_dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(bundle_dir.join("disk"), &hd_dir)?;
+    profile.save_args(&args)?;
+    output::status!("imported profile {name:?}, run rebox --profile {name} to use it");
+    Ok(())
+}
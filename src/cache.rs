@@ -0,0 +1,251 @@
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs::{self, TryLockError},
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{clean, output, qemu, store, util};
+
+/// File that is locked while the cache is changed
+pub fn lock_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("lock")
+}
+
+/// Exclusive lock on the cache directory, held while artifacts are downloaded, extracted, or
+/// removed, so concurrent runs do not write the same partial files. Released when dropped
+pub struct Lock {
+    _file: fs::File,
+}
+
+impl Lock {
+    /// Lock the cache directory, waiting for another rebox to release it
+    pub fn acquire(cache_dir: &Path) -> io::Result<Lock> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path(cache_dir))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                output::status!(
+                    "another rebox is preparing the cache, waiting for it (Ctrl-C to abort)"
+                );
+                file.lock()?;
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+        Ok(Lock { _file: file })
+    }
+}
+
+/// Path an artifact is written to until it is complete, which is removed by rebox clean
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+/// Sync a complete partial file and move it to path, so that path never has partial contents
+/// after a crash or an interrupted run
+pub fn commit(partial: &Path, path: &Path) -> io::Result<()> {
+    // Windows can only flush files opened for writing
+    let file = if cfg!(windows) {
+        fs::OpenOptions::new().write(true).open(partial)?
+    } else {
+        fs::File::open(partial)?
+    };
+    file.sync_all()?;
+    fs::rename(partial, path)?;
+    // The rename is only durable once the directory is synced
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Write data to path through a partial file
+pub fn write(path: &Path, data: impl AsRef<[u8]>) -> io::Result<()> {
+    let partial = partial_path(path);
+    fs::write(&partial, data)?;
+    commit(&partial, path)
+}
+
+/// File or directory in the cache that is created as a whole
+struct Artifact {
+    path: PathBuf,
+    kind: &'static str,
+    /// Name of a download in the store, which is named after its hash
+    stored: Option<String>,
+}
+
+/// Collect the artifacts in dir, and the paths of any other files
+fn artifacts(
+    cache_dir: &Path,
+    dir: &Path,
+    artifacts: &mut Vec<Artifact>,
+    other: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let kind = if name.ends_with(".partial") {
+            "partial"
+        } else if dir == store::dir(cache_dir) {
+            "download"
+        } else if dir == cache_dir.join("qemu") {
+            "QEMU source"
+        } else if path == cache_dir.join("qemu-build") {
+            "QEMU build"
+        } else if path.is_dir() {
+            self::artifacts(cache_dir, &path, artifacts, other)?;
+            continue;
+        } else if name.ends_with(".zst") {
+            "image"
+        } else if name == "harddrive.img" || name == "target.img" {
+            "harddrive"
+        } else if name == "livedisk.iso" {
+            "livedisk"
+        } else if dir == cache_dir && name.starts_with("qemu-") && name.ends_with(".tar.xz") {
+            "QEMU tarball"
+        } else if dir == cache_dir && name.starts_with("qemu-system-") && !name.contains('.') {
+            "QEMU binary"
+        } else {
+            other.push(path);
+            continue;
+        };
+        artifacts.push(Artifact {
+            path,
+            kind,
+            stored: None,
+        });
+    }
+    Ok(())
+}
+
+/// Whether the hash of an artifact is known, so it can be checked with rebox verify
+fn hash_status(artifact: &Artifact, recorded_images: &HashSet<String>) -> &'static str {
+    let name = artifact
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let known = match artifact.kind {
+        _ if artifact.stored.is_some() => true,
+        "image" => recorded_images.contains(&name),
+        "harddrive" | "livedisk" | "QEMU binary" => {
+            artifact.path.with_extension("sha256").is_file()
+        }
+        "QEMU tarball" => qemu::RELEASES
+            .iter()
+            .any(|release| format!("qemu-{}.tar.xz", release.version) == name),
+        "QEMU source" => artifact.path.join("rebox.sha256").is_file(),
+        _ => return "-",
+    };
+    if known {
+        "recorded"
+    } else {
+        "unknown"
+    }
+}
+
+/// Time since a file was last read or written
+fn last_used(path: &Path) -> io::Result<String> {
+    let metadata = fs::symlink_metadata(path)?;
+    let used = metadata.accessed().or_else(|_| metadata.modified())?;
+    let age = SystemTime::now().duration_since(used).unwrap_or_default();
+    Ok(format!("{} ago", util::format_duration(age.as_secs())))
+}
+
+/// Print the artifacts in the cache with their sizes, hash status, and last use
+pub fn info(cache_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let mut list = Vec::new();
+    let mut other = Vec::new();
+    artifacts(cache_dir, cache_dir, &mut list, &mut other)?;
+    list.sort_by(|a, b| a.path.cmp(&b.path));
+
+    // Downloads in the store are described by the index
+    let index = store::index(cache_dir)?;
+    for artifact in list
+        .iter_mut()
+        .filter(|artifact| artifact.kind == "download")
+    {
+        let sha256 = artifact
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let Some(entry) = index
+            .iter()
+            .find(|entry| entry.sha256.as_deref() == Some(&*sha256))
+        else {
+            continue;
+        };
+        artifact.kind = match entry.kind.as_str() {
+            "image" => "image",
+            "qemu" => "QEMU tarball",
+            _ => "download",
+        };
+        artifact.stored = Some(entry.name.clone());
+    }
+
+    // Compressed images recorded as the source of a harddrive or livedisk
+    let recorded_images: HashSet<String> = list
+        .iter()
+        .filter_map(|artifact| fs::read_to_string(artifact.path.with_extension("sha256")).ok())
+        .filter_map(|source| Some(source.lines().next()?.split_once("  ")?.1.to_string()))
+        .collect();
+
+    println!(
+        "{:<14} {:>10}  {:<8}  {:<12} PATH",
+        "KIND", "SIZE", "HASH", "LAST USED"
+    );
+    let mut total = 0;
+    for artifact in &list {
+        let size = clean::size(&artifact.path)?;
+        total += size;
+        println!(
+            "{:<14} {:>10}  {:<8}  {:<12} {}",
+            artifact.kind,
+            util::format_size(size),
+            hash_status(artifact, &recorded_images),
+            last_used(&artifact.path)?,
+            match &artifact.stored {
+                Some(name) => name.clone(),
+                None => artifact
+                    .path
+                    .strip_prefix(cache_dir)
+                    .unwrap_or(&artifact.path)
+                    .display()
+                    .to_string(),
+            }
+        );
+    }
+
+    let mut other_size = 0;
+    for path in &other {
+        other_size += clean::size(path)?;
+    }
+    total += other_size;
+    println!(
+        "{:<14} {:>10}  {:<8}  {:<12} {} files",
+        "other",
+        util::format_size(other_size),
+        "-",
+        "-",
+        other.len()
+    );
+    println!(
+        "total {} in {}",
+        util::format_size(total),
+        cache_dir.display()
+    );
+    Ok(())
+}
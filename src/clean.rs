@@ -0,0 +1,201 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{args::CleanOptions, output, qemu, store, util};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    /// Compressed image or QEMU source tarball, in the store or cached before it was used
+    Download,
+    /// Left behind by an interrupted download, decompression, or extraction
+    Partial,
+    /// Harddrive, extracted QEMU, or anything else
+    Other,
+}
+
+fn kind(path: &Path) -> Kind {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    if name.ends_with(".partial") {
+        Kind::Partial
+    } else if name.ends_with(".zst")
+        || (name.starts_with("qemu") && name.ends_with(".tar.xz"))
+        || path
+            .parent()
+            .is_some_and(|dir| dir.ends_with("store/sha256"))
+    {
+        Kind::Download
+    } else {
+        Kind::Other
+    }
+}
+
+/// Collect the files and directories that can be removed as a whole
+fn entries(cache_dir: &Path, dir: &Path, entries: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        // Partial, extracted, and build directories are removed as a whole
+        let whole = kind(&path) == Kind::Partial
+            || path == cache_dir.join("qemu")
+            || path == cache_dir.join("qemu-build");
+        if path.is_dir() && !whole {
+            self::entries(cache_dir, &path, entries)?;
+        } else {
+            entries.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Size of a file, or of everything in a directory
+pub fn size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut total = 0;
+        for entry in fs::read_dir(path)? {
+            total += size(&entry?.path())?;
+        }
+        Ok(total)
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+/// Downloads that were already decompressed or extracted: compressed images with a
+/// harddrive or livedisk created from them, and QEMU tarballs whose source was extracted
+pub fn intermediates(cache_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    entries(cache_dir, cache_dir, &mut paths)?;
+
+    let mut intermediates = Vec::new();
+    // Records of the images harddrives were created from, written once they are decompressed.
+    // Images may be shared by the harddrives of profiles, so they are found by name
+    for source_path in paths.iter().filter(|path| {
+        path.extension().is_some_and(|ext| ext == "sha256") && path.parent() != Some(cache_dir)
+    }) {
+        let source = fs::read_to_string(source_path)?;
+        let mut lines = source.lines().filter_map(|line| line.split_once("  "));
+        let (Some((image_sha256, image_name)), Some((_, hd_name))) = (lines.next(), lines.next())
+        else {
+            continue;
+        };
+        if !source_path.with_file_name(hd_name).is_file() {
+            continue;
+        }
+        let image_path = store::path(cache_dir, image_sha256);
+        if image_path.is_file() {
+            intermediates.push(image_path);
+        }
+        intermediates.extend(
+            paths
+                .iter()
+                .filter(|path| {
+                    kind(path) == Kind::Download
+                        && path.file_name().is_some_and(|name| name == image_name)
+                })
+                .cloned(),
+        );
+    }
+
+    // Records of the tarballs QEMU source was extracted from
+    for release in qemu::RELEASES {
+        let source_path = release.source_dir(cache_dir).join("rebox.sha256");
+        let Ok(source) = fs::read_to_string(&source_path) else {
+            continue;
+        };
+        let Some(sha256) = source.split_whitespace().next() else {
+            continue;
+        };
+        let mut tarball_paths = vec![store::path(cache_dir, sha256)];
+        if release.sha256 == sha256 {
+            tarball_paths.push(release.legacy_tarball_path(cache_dir));
+        }
+        intermediates.extend(tarball_paths.into_iter().filter(|path| path.is_file()));
+    }
+
+    intermediates.sort();
+    intermediates.dedup();
+    Ok(intermediates)
+}
+
+/// Remove the downloads that were already decompressed or extracted, after preparing a run
+pub fn auto(cache_dir: &Path) -> io::Result<()> {
+    for path in intermediates(cache_dir)? {
+        let path_size = size(&path)?;
+        output::status!(
+            "removing {} ({}), it was already decompressed",
+            path.display(),
+            util::format_size(path_size)
+        );
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+/// Remove files from the cache according to the options, keeping the files in keep
+pub fn run(
+    cache_dir: &Path,
+    options: &CleanOptions,
+    keep: &[PathBuf],
+) -> Result<(), Box<dyn Error>> {
+    let mut paths = Vec::new();
+    entries(cache_dir, cache_dir, &mut paths)?;
+    let intermediates = if options.intermediates {
+        intermediates(cache_dir)?
+    } else {
+        Vec::new()
+    };
+
+    let now = SystemTime::now();
+    let mut count = 0;
+    let mut total = 0;
+    for path in paths {
+        if keep.contains(&path) {
+            continue;
+        }
+
+        let remove = match kind(&path) {
+            _ if options.intermediates => intermediates.contains(&path),
+            Kind::Download => true,
+            Kind::Partial => !options.downloads_only,
+            Kind::Other => options.all,
+        };
+        if !remove {
+            continue;
+        }
+
+        if let Some(older_than) = options.older_than {
+            let modified = fs::symlink_metadata(&path)?.modified()?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age < older_than {
+                continue;
+            }
+        }
+
+        let path_size = size(&path)?;
+        output::status!(
+            "removing {} ({})",
+            path.display(),
+            util::format_size(path_size)
+        );
+        if path.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+        count += 1;
+        total += path_size;
+    }
+
+    output::status!(
+        "removed {count} files, freeing {}",
+        util::format_size(total)
+    );
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+use toml::{Table, Value};
+
+use crate::profile::Profile;
+
+/// Key of the arguments passed to QEMU, which is not an option
+const QEMU_ARGS: &str = "qemu-args";
+
+/// Configuration file with the defaults of the options, like memory = "4G" or headless = true
+pub fn path() -> io::Result<PathBuf> {
+    let config_dir = dirs::config_dir()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "user config directory not found"))?;
+    Ok(config_dir.join("rebox").join("config.toml"))
+}
+
+/// Load the options of the configuration file as arguments, which are empty if there is no
+/// configuration file
+///
+/// The options of a profile are in a [profile.NAME] section, and come after the global options
+/// so they override them
+pub fn load_args(profile: Option<&Profile>) -> Result<Vec<String>, String> {
+    let path = path().map_err(|err| err.to_string())?;
+    let data = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("failed to read {path:?}: {err}")),
+    };
+    let mut table: Table = toml::from_str(&data).map_err(|err| {
+        let line = err
+            .span()
+            .map_or(0, |span| data[..span.start].lines().count());
+        format!(
+            "failed to parse {path:?} at line {line}: {}",
+            err.message().trim_end().replace('\n', ", ")
+        )
+    })?;
+    let profiles = match table.remove("profile") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(format!(
+                "invalid profile in {path:?}, expected sections like [profile.NAME]"
+            ))
+        }
+        None => Table::new(),
+    };
+    let mut tables = vec![&table];
+    if let Some(profile) = profile {
+        match profiles.get(&profile.name) {
+            Some(Value::Table(section)) => tables.push(section),
+            Some(_) => return Err(format!("invalid profile.{} in {path:?}", profile.name)),
+            None => {}
+        }
+    }
+    args(&tables).map_err(|err| format!("invalid option in {path:?}: {err}"))
+}
+
+/// Convert tables of options to arguments, in order. Keys are the long names of options, with
+/// - or _ between words, and qemu-args is a list of arguments passed to QEMU
+fn args(tables: &[&Table]) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut qemu_args = Vec::new();
+    for (key, value) in tables.iter().flat_map(|table| table.iter()) {
+        let name = key.replace('_', "-");
+        if name == QEMU_ARGS {
+            for arg in array(key, value)? {
+                qemu_args.push(string(key, arg)?);
+            }
+            continue;
+        }
+        if name == "profile" || name == "save" {
+            return Err(format!("{key} can only be given on the command line"));
+        }
+        let option = format!("--{name}");
+        match value {
+            Value::Boolean(true) => args.push(option),
+            Value::Boolean(false) => {}
+            Value::Array(values) => {
+                for value in values {
+                    args.push(option.clone());
+                    args.push(string(key, value)?);
+                }
+            }
+            value => {
+                args.push(option);
+                args.push(string(key, value)?);
+            }
+        }
+    }
+    if !qemu_args.is_empty() {
+        args.push("--".to_string());
+        args.extend(qemu_args);
+    }
+    Ok(args)
+}
+
+fn array<'a>(key: &str, value: &'a Value) -> Result<&'a [Value], String> {
+    match value {
+        Value::Array(values) => Ok(values),
+        _ => Err(format!("{key} must be a list")),
+    }
+}
+
+/// Value of an option as an argument
+fn string(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Integer(value) => Ok(value.to_string()),
+        _ => Err(format!("{key} must be a string, an integer, or true")),
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    error::Error,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::profile::Profile;
+
+/// Chardev of the console, which multiplexes the first serial port and the QEMU monitor
+const CHARDEV: &str = "console";
+
+/// Connect the first serial port and the QEMU monitor to the terminal in raw mode
+///
+/// Keys like Ctrl-c are sent to the guest, and Ctrl-a is the escape key, so Ctrl-a x quits
+/// and Ctrl-a c switches between the serial port and the monitor. Output is also written to
+/// log if given
+pub fn args(command: &mut Command, log: Option<&Path>) {
+    let mut chardev = format!("stdio,id={CHARDEV},mux=on,signal=off");
+    if let Some(log) = log {
+        chardev.push_str(&format!(",logfile={},logappend=on", log.display()));
+    }
+    command.arg("-chardev").arg(chardev);
+    command.arg("-serial").arg(format!("chardev:{CHARDEV}"));
+    command
+        .arg("-mon")
+        .arg(format!("chardev={CHARDEV},mode=readline"));
+}
+
+/// Key that detaches from the serial console or monitor of a VM, Ctrl-]
+const DETACH_KEY: u8 = 0x1d;
+
+/// Path of the monitor socket of a detached VM
+pub fn monitor_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "monitor")
+}
+
+/// Add a socket for the human monitor, removing the socket left behind by a previous run
+pub fn monitor_args(command: &mut Command, path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    command
+        .arg("-monitor")
+        .arg(format!("unix:{},server=on,wait=off", path.display()));
+    Ok(())
+}
+
+/// Connect the terminal in raw mode to a socket of a detached VM, like its serial console,
+/// until Ctrl-] is pressed or the VM exits
+#[cfg(unix)]
+pub fn attach(path: &Path) -> Result<(), Box<dyn Error>> {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        process, thread,
+    };
+
+    let stream = UnixStream::connect(path).map_err(|err| {
+        format!(
+            "failed to connect to {}, is the VM running with --detach? {err}",
+            path.display()
+        )
+    })?;
+    let terminal = Terminal::save();
+    terminal.raw();
+    // Lines need a carriage return in raw mode
+    print!("attached to {}, press Ctrl-] to detach\r\n", path.display());
+    io::stdout().flush()?;
+
+    let mut reader = stream.try_clone()?;
+    let settings = terminal.settings.clone();
+    thread::spawn(move || {
+        // Output is flushed as it arrives, as prompts do not end with a newline, and stdout
+        // is not kept locked so the main thread can print when detaching
+        let mut buf = [0; 1024];
+        while let Ok(count @ 1..) = reader.read(&mut buf) {
+            let mut stdout = io::stdout();
+            if stdout
+                .write_all(&buf[..count])
+                .and_then(|()| stdout.flush())
+                .is_err()
+            {
+                break;
+            }
+        }
+        // Reading from the terminal cannot be interrupted, so exit here when the VM does
+        Terminal { settings }.restore();
+        println!("\nVM exited");
+        process::exit(0);
+    });
+
+    let mut writer = stream;
+    let mut stdin = io::stdin().lock();
+    let mut buf = [0; 1024];
+    loop {
+        let count = stdin.read(&mut buf)?;
+        if count == 0 {
+            break;
+        }
+        let input = &buf[..count];
+        match input.iter().position(|&b| b == DETACH_KEY) {
+            Some(i) => {
+                writer.write_all(&input[..i])?;
+                break;
+            }
+            None => writer.write_all(input)?,
+        }
+    }
+    drop(terminal);
+    println!("\ndetached");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn attach(_path: &Path) -> Result<(), Box<dyn Error>> {
+    Err("attaching to a VM is only supported on Unix hosts".into())
+}
+
+/// Settings of the terminal, restored when dropped in case QEMU exits without doing so
+pub struct Terminal {
+    settings: Option<String>,
+}
+
+impl Terminal {
+    /// Save the settings of the terminal, doing nothing if they cannot be read
+    pub fn save() -> Self {
+        let settings = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        if settings.is_none() {
+            log::debug!("unable to save terminal settings");
+        }
+        Self { settings }
+    }
+
+    /// Pass keys to the program without processing or echoing them, if the settings were saved
+    #[cfg(unix)]
+    fn raw(&self) {
+        if self.settings.is_some() {
+            let _ = Command::new("stty")
+                .args(["raw", "-echo"])
+                .stdin(Stdio::inherit())
+                .status();
+        }
+    }
+
+    fn restore(&self) {
+        if let Some(settings) = &self.settings {
+            let _ = Command::new("stty")
+                .arg(settings)
+                .stdin(Stdio::inherit())
+                .status();
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, process::Command, str::FromStr};
+
+use crate::arch::Machine;
+
+/// Storage controller the harddrive is attached to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiskInterface {
+    Nvme,
+    Virtio,
+    Ahci,
+    Ide,
+}
+
+impl DiskInterface {
+    pub const ALL: [DiskInterface; 4] = [
+        DiskInterface::Nvme,
+        DiskInterface::Virtio,
+        DiskInterface::Ahci,
+        DiskInterface::Ide,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DiskInterface::Nvme => "nvme",
+            DiskInterface::Virtio => "virtio",
+            DiskInterface::Ahci => "ahci",
+            DiskInterface::Ide => "ide",
+        }
+    }
+
+    /// Storage controller used when none is selected, the one built into the machine if any
+    pub fn default_for(machine: Machine) -> DiskInterface {
+        match machine {
+            Machine::Q35 => DiskInterface::Ahci,
+            Machine::Pc => DiskInterface::Ide,
+            // There is no storage controller built into virt
+            Machine::Virt => DiskInterface::Nvme,
+            // Only paravirtualized devices are used with microvm
+            Machine::Microvm => DiskInterface::Virtio,
+        }
+    }
+
+    /// Check that the controller can be used with the machine
+    pub fn check(&self, machine: Machine) -> Result<(), String> {
+        // IDE is only available as the controller built into pc
+        if *self == DiskInterface::Ide && machine != Machine::Pc {
+            return Err(format!(
+                "ide disk interface requires the pc machine, not {machine}"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DiskInterface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DiskInterface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nvme" => Ok(DiskInterface::Nvme),
+            "virtio" => Ok(DiskInterface::Virtio),
+            "ahci" => Ok(DiskInterface::Ahci),
+            "ide" => Ok(DiskInterface::Ide),
+            _ => Err(format!(
+                "unknown disk interface {s:?}, expected one of {}",
+                DiskInterface::ALL
+                    .map(|interface| interface.name())
+                    .join(", ")
+            )),
+        }
+    }
+}
+
+/// Add the harddrive with the controller and device for the interface
+pub fn drive(
+    command: &mut Command,
+    machine: Machine,
+    interface: DiskInterface,
+    path: &str,
+    format: &str,
+    ephemeral: bool,
+) {
+    // With snapshot=on, writes go to a temporary overlay that QEMU deletes on exit
+    let snapshot = if ephemeral { ",snapshot=on" } else { "" };
+    let builtin = matches!(
+        (machine, interface),
+        (Machine::Q35, DiskInterface::Ahci) | (Machine::Pc, DiskInterface::Ide)
+    );
+    if builtin {
+        // Attached to the first port of the built in controller
+        command
+            .arg("-drive")
+            .arg(format!("file={path},format={format}{snapshot}"));
+        return;
+    }
+
+    command.arg("-drive").arg(format!(
+        "file={path},format={format},if=none,id=drv0{snapshot}"
+    ));
+    match interface {
+        DiskInterface::Nvme => {
+            command
+                .arg("-device")
+                .arg("nvme,drive=drv0,serial=NVME_SERIAL");
+        }
+        DiskInterface::Virtio => {
+            command.arg("-device").arg("virtio-blk-pci,drive=drv0");
+        }
+        DiskInterface::Ahci => {
+            command.arg("-device").arg("ahci,id=ahci0");
+            command.arg("-device").arg("ide-hd,drive=drv0,bus=ahci0.0");
+        }
+        DiskInterface::Ide => {
+            // Rejected by DiskInterface::check
+            command.arg("-device").arg("ide-hd,drive=drv0");
+        }
+    }
+}
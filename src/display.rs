@@ -0,0 +1,173 @@
+use std::{ffi::OsStr, fmt, process::Command, str::FromStr};
+
+use crate::gpu::Gpu;
+
+/// QEMU display backend showing the guest in a window
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Display {
+    Gtk,
+    Sdl,
+    Cocoa,
+    Dbus,
+    None,
+}
+
+impl Display {
+    pub const ALL: [Display; 5] = [
+        Display::Gtk,
+        Display::Sdl,
+        Display::Cocoa,
+        Display::Dbus,
+        Display::None,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Display::Gtk => "gtk",
+            Display::Sdl => "sdl",
+            Display::Cocoa => "cocoa",
+            Display::Dbus => "dbus",
+            Display::None => "none",
+        }
+    }
+
+    /// Check if the backend can show OpenGL output, as needed by virgl
+    fn has_gl(&self) -> bool {
+        matches!(self, Display::Gtk | Display::Sdl | Display::Dbus)
+    }
+
+    /// Check if the backend can scale the guest to the size of the window
+    fn has_zoom_to_fit(&self) -> bool {
+        matches!(self, Display::Gtk | Display::Cocoa)
+    }
+
+    /// Backends to try when the selected one is not available, best first
+    fn fallbacks() -> &'static [Display] {
+        if cfg!(target_os = "macos") {
+            &[Display::Cocoa, Display::Sdl]
+        } else {
+            &[Display::Gtk, Display::Sdl]
+        }
+    }
+}
+
+/// Display backends built into a QEMU binary, or None if they cannot be listed
+fn supported(qemu_system: &OsStr) -> Option<Vec<Display>> {
+    // Output is a header followed by one backend per line
+    let output = Command::new(qemu_system)
+        .arg("-display")
+        .arg("help")
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect(),
+    )
+}
+
+/// Pick a display backend that QEMU supports, starting with the selected one
+fn available(
+    display: Option<Display>,
+    gpu: Gpu,
+    zoom_to_fit: bool,
+    qemu_system: &OsStr,
+) -> Option<Display> {
+    let wanted = display.or_else(|| {
+        // Only virgl and options of the window need a specific backend, otherwise QEMU picks
+        // its default
+        (gpu == Gpu::Virgl || zoom_to_fit).then(|| Display::fallbacks()[0])
+    })?;
+    let Some(supported) = supported(qemu_system) else {
+        return Some(wanted);
+    };
+    if supported.contains(&wanted) {
+        return Some(wanted);
+    }
+    let fallback = Display::fallbacks()
+        .iter()
+        .copied()
+        .find(|display| supported.contains(display))
+        .unwrap_or(Display::None);
+    log::warn!("QEMU does not support the {wanted} display, using {fallback} instead");
+    Some(fallback)
+}
+
+/// Value of the QEMU -display option for the backend and graphics device, or None to use the
+/// default. When headless, no window is opened
+pub fn arg(
+    display: Option<Display>,
+    gpu: Gpu,
+    headless: bool,
+    zoom_to_fit: bool,
+    qemu_system: &OsStr,
+) -> Option<String> {
+    if headless {
+        // Renders without a window, so virgl still works
+        return Some(
+            if gpu == Gpu::Virgl {
+                "egl-headless"
+            } else {
+                "none"
+            }
+            .to_string(),
+        );
+    }
+    let display = available(display, gpu, zoom_to_fit, qemu_system)?;
+    let mut arg = display.to_string();
+    if gpu == Gpu::Virgl {
+        if display.has_gl() {
+            // GL output requires a display backend that supports it
+            arg.push_str(",gl=on");
+        } else {
+            log::warn!("the {display} display does not support OpenGL, virgl may not work");
+        }
+    }
+    if zoom_to_fit {
+        if display.has_zoom_to_fit() {
+            arg.push_str(",zoom-to-fit=on");
+        } else if display != Display::Sdl {
+            // SDL windows always scale the guest when resized
+            log::warn!("the {display} display cannot zoom to fit the window");
+        }
+    }
+    Some(arg)
+}
+
+/// Scale the window by a whole factor for HiDPI monitors, which only GTK supports
+pub fn scale(command: &mut Command, display: Option<Display>, scale: u32) {
+    match display {
+        Some(Display::Gtk) | None => {
+            command.env("GDK_SCALE", scale.to_string());
+        }
+        Some(display) => log::warn!("the {display} display cannot be scaled"),
+    }
+}
+
+impl fmt::Display for Display {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Display {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gtk" => Ok(Display::Gtk),
+            "sdl" => Ok(Display::Sdl),
+            "cocoa" => Ok(Display::Cocoa),
+            "dbus" => Ok(Display::Dbus),
+            "none" => Ok(Display::None),
+            _ => Err(format!(
+                "unknown display {s:?}, expected one of {}",
+                Display::ALL.map(|display| display.name()).join(", ")
+            )),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{arch::Arch, output, util};
+
+/// Port of the GDB stub when none is given, the one QEMU uses for -s
+pub const DEFAULT_PORT: u16 = 1234;
+
+/// Add a GDB stub on localhost, with the CPUs stopped until the debugger continues them
+pub fn args(command: &mut Command, port: u16) {
+    command
+        .arg("-gdb")
+        .arg(format!("tcp:127.0.0.1:{port}"))
+        .arg("-S");
+}
+
+/// Kernel with symbols built in the Redox source tree that image was built in, if it was
+fn kernel_symbols(arch: Arch, image: &Path) -> Option<PathBuf> {
+    // Images are built at build/<ARCH>/<CONFIG>/harddrive.img
+    let source_dir = image.ancestors().nth(4)?;
+    let target = format!("{}-unknown-redox", arch.name());
+    // Older source trees keep recipes in the cookbook
+    ["recipes", "cookbook/recipes"]
+        .iter()
+        .map(|recipes| {
+            source_dir
+                .join(recipes)
+                .join("core/kernel/target")
+                .join(&target)
+                .join("build/kernel.sym")
+        })
+        .find(|path| path.is_file())
+}
+
+/// Run GDB with the kernel symbols, connected to the stub of a VM started with --gdb
+pub fn run(
+    arch: Arch,
+    port: u16,
+    kernel: Option<&Path>,
+    image: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let kernel = match kernel {
+        Some(kernel) => kernel.to_path_buf(),
+        None => image
+            .and_then(|image| kernel_symbols(arch, image))
+            .ok_or("kernel symbols not found, give the path of kernel.sym with --kernel")?,
+    };
+
+    // GDB for the host may not support other architectures
+    let gdb = if arch.is_native() {
+        util::which("gdb")
+    } else {
+        util::which("gdb-multiarch").or_else(|| util::which("gdb"))
+    }
+    .ok_or("gdb not found, please install it")?;
+    let mut command = Command::new(gdb);
+    command
+        .arg(&kernel)
+        .arg("-ex")
+        .arg(format!("target remote 127.0.0.1:{port}"));
+    output::status!("running {:?}", command);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let err = command.exec();
+        Err(format!("failed to run gdb: {err}").into())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let status = command.status()?;
+        if !status.success() {
+            return Err(format!("gdb exited with {status}").into());
+        }
+        Ok(())
+    }
+}
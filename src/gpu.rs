@@ -0,0 +1,143 @@
+use std::{fmt, str::FromStr};
+
+/// Graphics device of the guest
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Gpu {
+    /// VGA device of the machine, or a simple framebuffer if there is none
+    #[default]
+    Std,
+    /// Paravirtualized virtio-gpu
+    Virtio,
+    /// virtio-gpu with OpenGL acceleration through virglrenderer
+    Virgl,
+}
+
+impl Gpu {
+    pub const ALL: [Gpu; 3] = [Gpu::Std, Gpu::Virtio, Gpu::Virgl];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gpu::Std => "std",
+            Gpu::Virtio => "virtio",
+            Gpu::Virgl => "virgl",
+        }
+    }
+}
+
+impl fmt::Display for Gpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Gpu {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(Gpu::Std),
+            "virtio" => Ok(Gpu::Virtio),
+            "virgl" => Ok(Gpu::Virgl),
+            _ => Err(format!(
+                "unknown GPU {s:?}, expected one of {}",
+                Gpu::ALL.map(|gpu| gpu.name()).join(", ")
+            )),
+        }
+    }
+}
+
+/// VGA device of an x86 machine
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Vga {
+    #[default]
+    Std,
+    Cirrus,
+    Vmware,
+    Qxl,
+}
+
+impl Vga {
+    pub const ALL: [Vga; 4] = [Vga::Std, Vga::Cirrus, Vga::Vmware, Vga::Qxl];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Vga::Std => "std",
+            Vga::Cirrus => "cirrus",
+            Vga::Vmware => "vmware",
+            Vga::Qxl => "qxl",
+        }
+    }
+
+    /// Name of the QEMU device
+    pub fn device(&self) -> &'static str {
+        match self {
+            Vga::Std => "VGA",
+            Vga::Cirrus => "cirrus-vga",
+            Vga::Vmware => "vmware-svga",
+            Vga::Qxl => "qxl-vga",
+        }
+    }
+
+    /// Check if the device can report a preferred resolution to the guest
+    pub fn has_resolution(&self) -> bool {
+        matches!(self, Vga::Std | Vga::Qxl)
+    }
+}
+
+impl fmt::Display for Vga {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Vga {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(Vga::Std),
+            "cirrus" => Ok(Vga::Cirrus),
+            "vmware" => Ok(Vga::Vmware),
+            "qxl" => Ok(Vga::Qxl),
+            _ => Err(format!(
+                "unknown VGA device {s:?}, expected one of {}",
+                Vga::ALL.map(|vga| vga.name()).join(", ")
+            )),
+        }
+    }
+}
+
+/// Preferred display resolution reported to the guest
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Resolution {
+    /// Properties of the graphics device that set the preferred resolution
+    pub fn props(&self) -> String {
+        format!(",xres={},yres={}", self.width, self.height)
+    }
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid resolution {s:?}, expected WIDTHxHEIGHT like 1920x1080");
+        let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+        let width = width.parse().map_err(|_| invalid())?;
+        let height = height.parse().map_err(|_| invalid())?;
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+        Ok(Resolution { width, height })
+    }
+}
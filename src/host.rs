@@ -0,0 +1,76 @@
+use std::{collections::HashSet, fs, process::Command, thread};
+
+/// Read a value in bytes from /proc/meminfo, like MemTotal
+fn meminfo(key: &str) -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    // Lines look like "MemTotal:       16318412 kB"
+    let line = meminfo
+        .lines()
+        .find(|line| line.split(':').next() == Some(key))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Total memory of the host in bytes, if it can be detected
+pub fn total_memory() -> Option<u64> {
+    if cfg!(target_os = "macos") {
+        let output = Command::new("sysctl")
+            .arg("-n")
+            .arg("hw.memsize")
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+    } else {
+        meminfo("MemTotal")
+    }
+}
+
+/// Memory of the host in bytes that is available without swapping, if it can be detected
+pub fn available_memory() -> Option<u64> {
+    meminfo("MemAvailable")
+}
+
+/// Number of CPU threads of the host
+pub fn logical_cpus() -> usize {
+    thread::available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+/// Number of physical CPU cores of the host, without hyperthreads
+pub fn physical_cores() -> usize {
+    let cores = if cfg!(target_os = "macos") {
+        Command::new("sysctl")
+            .arg("-n")
+            .arg("hw.physicalcpu")
+            .output()
+            .ok()
+            .and_then(|output| String::from_utf8_lossy(&output.stdout).trim().parse().ok())
+    } else {
+        cpuinfo_cores()
+    };
+    cores.unwrap_or_else(logical_cpus)
+}
+
+/// Count the unique physical id and core id pairs in /proc/cpuinfo
+fn cpuinfo_cores() -> Option<usize> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").ok()?;
+    let mut cores = HashSet::new();
+    // Processors are separated by empty lines
+    for processor in cpuinfo.split("\n\n") {
+        let mut physical_id = None;
+        let mut core_id = None;
+        for line in processor.lines() {
+            match line.split_once(':') {
+                Some((key, value)) if key.trim() == "physical id" => {
+                    physical_id = Some(value.trim())
+                }
+                Some((key, value)) if key.trim() == "core id" => core_id = Some(value.trim()),
+                _ => {}
+            }
+        }
+        if let (Some(physical_id), Some(core_id)) = (physical_id, core_id) {
+            cores.insert((physical_id, core_id));
+        }
+    }
+    // Some architectures do not report cores
+    (!cores.is_empty()).then_some(cores.len())
+}
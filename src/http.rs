@@ -0,0 +1,232 @@
+use std::{
+    io::{self, Write},
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use reqwest::{blocking::Client, NoProxy, Proxy, StatusCode};
+
+/// Times a failed request is retried when not configured
+pub const DEFAULT_RETRIES: u32 = 5;
+
+/// Parallel connections used for a download when not configured
+pub const DEFAULT_CONNECTIONS: u64 = 1;
+
+/// Longest wait between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Server that hosts copies of the files of another server
+#[derive(Clone, Debug)]
+pub struct Mirror {
+    /// URL of the original server, like https://static.redox-os.org
+    pub server: &'static str,
+    /// URL of the mirror, which files are requested from with the same paths
+    pub url: String,
+}
+
+/// Settings for all requests, from the command line
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// Times a request that failed with a transient error is retried
+    pub retries: u32,
+    /// Mirrors tried before the original servers, in order
+    pub mirrors: Vec<Mirror>,
+    /// Parallel range requests a large download is split into
+    pub connections: u64,
+    /// Forbid all requests, only using what is already cached
+    pub offline: bool,
+    /// Proxy for all requests, instead of the one from HTTP_PROXY, HTTPS_PROXY, or ALL_PROXY
+    pub proxy: Option<Proxy>,
+    /// Bytes per second all downloads together are limited to
+    pub limit_rate: Option<u64>,
+    /// Download images over BitTorrent when they have a torrent
+    pub torrent: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            retries: DEFAULT_RETRIES,
+            mirrors: Vec::new(),
+            connections: DEFAULT_CONNECTIONS,
+            offline: false,
+            proxy: None,
+            limit_rate: None,
+            torrent: false,
+        }
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Use settings for all requests, which must be done before any are made
+pub fn configure(settings: Settings) {
+    if SETTINGS.set(settings).is_err() {
+        log::warn!("HTTP settings were already configured");
+    }
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// Parallel range requests a large download is split into
+pub fn connections() -> u64 {
+    settings().connections
+}
+
+/// Bytes per second all downloads together are limited to
+pub fn limit_rate() -> Option<u64> {
+    settings().limit_rate
+}
+
+/// Check if images are downloaded over BitTorrent
+pub fn torrent() -> bool {
+    settings().torrent
+}
+
+/// Check if requests are forbidden
+pub fn offline() -> bool {
+    settings().offline
+}
+
+/// Fail if requests are forbidden, before requesting url
+pub fn check_online(url: &str) -> io::Result<()> {
+    if offline() {
+        Err(io::Error::other(format!(
+            "{url} is not cached and --offline forbids downloading it"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Start of the current burst of downloading and the bytes downloaded since, shared by all
+/// downloads so parallel connections are limited together
+static THROTTLE: Mutex<Option<(Instant, u64)>> = Mutex::new(None);
+
+/// Account for count downloaded bytes, sleeping as long as needed to stay under the rate limit
+pub fn throttle(count: u64) {
+    let Some(rate) = settings().limit_rate else {
+        return;
+    };
+    let wait = {
+        let mut throttle = THROTTLE.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        let (start, bytes) = throttle.get_or_insert((now, 0));
+        *bytes += count;
+        let due = *start + Duration::from_secs_f64(*bytes as f64 / rate as f64);
+        if now > due + Duration::from_secs(1) {
+            // Downloading was paused, so start over instead of catching up in a burst
+            *throttle = Some((now, count));
+            Duration::ZERO
+        } else {
+            due.saturating_duration_since(now)
+        }
+    };
+    thread::sleep(wait);
+}
+
+/// Writer for downloaded data that keeps to the rate limit
+pub struct Throttled<W: Write>(pub W);
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.0.write(buf)?;
+        throttle(count as u64);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Client for requests using the settings
+///
+/// Without a configured proxy, reqwest uses the proxy environment variables. Hosts in NO_PROXY
+/// bypass either
+pub fn client() -> Client {
+    let mut builder = Client::builder();
+    if let Some(proxy) = &settings().proxy {
+        builder = builder.proxy(proxy.clone().no_proxy(NoProxy::from_env()));
+    }
+    builder.build().expect("failed to build HTTP client")
+}
+
+/// Check if a request that failed may succeed when retried, like after a connection reset or
+/// a server error, rather than a missing file
+pub fn is_transient(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => {
+            status.is_server_error()
+                || status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+    }
+}
+
+/// Wait before retrying after a number of failed attempts, doubling each time
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(5)).min(MAX_BACKOFF)
+}
+
+/// Wait before retrying a request that failed with err, returning false without waiting if
+/// it should not be retried because the error is permanent or there were too many attempts
+pub fn wait_to_retry(url: &str, attempt: &mut u32, err: &reqwest::Error) -> bool {
+    let retries = settings().retries;
+    if *attempt >= retries || !is_transient(err) {
+        return false;
+    }
+    let delay = backoff(*attempt);
+    *attempt += 1;
+    log::warn!(
+        "request to {url} failed, retrying in {}s ({attempt}/{retries}): {err}",
+        delay.as_secs()
+    );
+    thread::sleep(delay);
+    true
+}
+
+/// Run a request until it succeeds, retrying transient failures with exponential backoff
+pub fn retry<T>(url: &str, mut request: impl FnMut() -> reqwest::Result<T>) -> reqwest::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match request() {
+            Err(err) if wait_to_retry(url, &mut attempt, &err) => {}
+            res => return res,
+        }
+    }
+}
+
+/// URLs to request url from, on each mirror of its server in order and then from url itself
+pub fn mirror_urls(url: &str) -> Vec<String> {
+    let mut urls: Vec<String> = settings()
+        .mirrors
+        .iter()
+        .filter_map(|mirror| {
+            let path = url.strip_prefix(mirror.server)?;
+            Some(format!("{}{path}", mirror.url.trim_end_matches('/')))
+        })
+        .collect();
+    urls.push(url.to_string());
+    urls
+}
+
+/// Run a request for url on each of its mirrors until one succeeds, with retries
+pub fn failover<T>(
+    url: &str,
+    mut request: impl FnMut(&str) -> reqwest::Result<T>,
+) -> reqwest::Result<T> {
+    let urls = mirror_urls(url);
+    let (last, mirrors) = urls.split_last().expect("url is always included");
+    for mirror in mirrors {
+        match retry(mirror, || request(mirror)) {
+            Ok(res) => return Ok(res),
+            Err(err) => log::warn!("request to {mirror} failed, trying the next server: {err}"),
+        }
+    }
+    retry(last, || request(last))
+}
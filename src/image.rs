@@ -0,0 +1,962 @@
+use std::{
+    error::Error,
+    ffi::OsStr,
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    thread,
+};
+
+use crate::{arch::Arch, cache, http, offline::Missing, output, store, torrent, util, verify};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Variant {
+    #[default]
+    Demo,
+    Desktop,
+    Server,
+    Minimal,
+}
+
+impl Variant {
+    pub const ALL: [Variant; 4] = [
+        Variant::Demo,
+        Variant::Desktop,
+        Variant::Server,
+        Variant::Minimal,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Variant::Demo => "demo",
+            Variant::Desktop => "desktop",
+            Variant::Server => "server",
+            Variant::Minimal => "minimal",
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "demo" => Ok(Variant::Demo),
+            "desktop" => Ok(Variant::Desktop),
+            "server" => Ok(Variant::Server),
+            "minimal" => Ok(Variant::Minimal),
+            _ => Err(format!(
+                "unknown variant {s:?}, expected one of {}",
+                Variant::ALL.map(|variant| variant.name()).join(", ")
+            )),
+        }
+    }
+}
+
+/// Server that Redox images are published on
+pub const SERVER: &str = "https://static.redox-os.org";
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Channel {
+    /// Images published on the static server
+    #[default]
+    Stable,
+    /// Daily builds
+    Nightly,
+}
+
+impl Channel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Nightly => "nightly",
+        }
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "nightly" => Ok(Channel::Nightly),
+            _ => Err(format!(
+                "unknown channel {s:?}, expected one of stable, nightly"
+            )),
+        }
+    }
+}
+
+/// Location of published images on the Redox server
+#[derive(Clone, Debug)]
+pub struct Remote {
+    pub arch: Arch,
+    pub channel: Channel,
+    /// Release version, or None for the latest images of the channel
+    pub release: Option<String>,
+    /// OpenPGP public key that must have signed SHA256SUM
+    pub signing_key: Option<PathBuf>,
+}
+
+impl Remote {
+    pub fn url(&self) -> String {
+        match &self.release {
+            Some(release) => format!("{SERVER}/releases/{release}/{}", self.arch),
+            None => match self.channel {
+                Channel::Stable => format!("{SERVER}/img/{}", self.arch),
+                Channel::Nightly => format!("{SERVER}/nightly/{}", self.arch),
+            },
+        }
+    }
+
+    /// Cache directory for harddrives created from this location
+    pub fn cache_dir(&self, cache_dir: &Path) -> PathBuf {
+        match &self.release {
+            Some(release) => cache_dir
+                .join("releases")
+                .join(release)
+                .join(self.arch.name()),
+            None => match self.channel {
+                Channel::Stable => cache_dir.join(self.arch.name()),
+                Channel::Nightly => cache_dir.join("nightly").join(self.arch.name()),
+            },
+        }
+    }
+}
+
+pub struct RemoteImage {
+    pub name: String,
+    pub sha256: String,
+    pub url: String,
+}
+
+impl RemoteImage {
+    /// Build date, from image names like redox_demo_x86_64_2024-09-07_1225_harddrive.img.zst
+    pub fn date(&self) -> Option<&str> {
+        date(&self.name)
+    }
+}
+
+/// Build date from the name of an image
+fn date(name: &str) -> Option<&str> {
+    name.split('_').find(|part| {
+        part.len() == 10
+            && part.chars().enumerate().all(|(i, c)| {
+                if i == 4 || i == 7 {
+                    c == '-'
+                } else {
+                    c.is_ascii_digit()
+                }
+            })
+    })
+}
+
+/// Download url to path unless the copy at path is still current, returning its contents and
+/// whether it changed
+///
+/// The ETag and Last-Modified headers of the download are kept next to it, in path with a
+/// .headers extension, and sent back with If-None-Match and If-Modified-Since so the server
+/// only sends the file again if it changed
+fn revalidate(url: &str, path: &Path) -> Result<(String, bool), Box<dyn Error>> {
+    let headers_path = path.with_extension("headers");
+    let cached = fs::read_to_string(path).ok();
+    let validators = match cached {
+        Some(_) => fs::read_to_string(&headers_path).unwrap_or_default(),
+        None => String::new(),
+    };
+    let client = http::client();
+    let fetched = http::failover(url, |url| {
+        let mut request = client.get(url);
+        for line in validators.lines() {
+            if let Some(etag) = line.strip_prefix("ETag: ") {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            } else if let Some(date) = line.strip_prefix("Last-Modified: ") {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, date);
+            }
+        }
+        let resp = request.send()?.error_for_status()?;
+        if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let mut headers = String::new();
+        for (header, name) in [
+            (reqwest::header::ETAG, "ETag"),
+            (reqwest::header::LAST_MODIFIED, "Last-Modified"),
+        ] {
+            if let Some(value) = resp.headers().get(header).and_then(|v| v.to_str().ok()) {
+                headers.push_str(&format!("{name}: {value}\n"));
+            }
+        }
+        Ok(Some((resp.text()?, headers)))
+    })?;
+    match (fetched, cached) {
+        (Some((data, headers)), _) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            cache::write(path, &data)?;
+            cache::write(&headers_path, headers)?;
+            Ok((data, true))
+        }
+        (None, Some(cached)) => {
+            log::info!("{url} has not changed since it was cached");
+            Ok((cached, false))
+        }
+        (None, None) => Err(format!("{url} was not modified, but it is not cached").into()),
+    }
+}
+
+/// Fetch the list of images published at a location
+///
+/// The list is cached in the cache directory of the location, and only downloaded again when
+/// the server has a different one
+pub fn remote_images(
+    cache_dir: &Path,
+    remote: &Remote,
+) -> Result<Vec<RemoteImage>, Box<dyn Error>> {
+    let img_url = remote.url();
+    let shasum_url = format!("{img_url}/SHA256SUM");
+    http::check_online(&shasum_url)?;
+    let shasum_path = remote.cache_dir(cache_dir).join("SHA256SUM");
+    let (shasum, changed) = revalidate(&shasum_url, &shasum_path)?;
+    if let Some(signing_key) = &remote.signing_key {
+        // The signature only changes with the list
+        let signature_path = shasum_path.with_extension("asc");
+        let signature = match fs::read(&signature_path) {
+            Ok(signature) if !changed => signature,
+            _ => {
+                let client = http::client();
+                let signature = http::failover(&format!("{shasum_url}.asc"), |url| {
+                    client.get(url).send()?.error_for_status()?.bytes()
+                })?;
+                cache::write(&signature_path, &signature)?;
+                signature.to_vec()
+            }
+        };
+        verify::signature(shasum.as_bytes(), &signature, signing_key)
+            .map_err(|err| format!("failed to verify {shasum_url}: {err}"))?;
+    }
+    Ok(parse_shasum(&img_url, &shasum))
+}
+
+/// Images listed in SHA256SUM format, with lines like "HASH  NAME", skipping lines that are not
+fn parse_shasum(img_url: &str, shasum: &str) -> Vec<RemoteImage> {
+    let mut images = Vec::new();
+    for line in shasum.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(sha256), Some(name), None) = (parts.next(), parts.next(), parts.next()) else {
+            continue;
+        };
+        // A leading * marks a file hashed in binary mode
+        let name = name.strip_prefix('*').unwrap_or(name);
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            continue;
+        }
+        images.push(RemoteImage {
+            name: name.to_string(),
+            sha256: sha256.to_string(),
+            url: format!("{img_url}/{name}"),
+        });
+    }
+    images
+}
+
+/// Size requests sent at once when listing images, as there is one for each image
+const SIZE_REQUESTS: usize = 8;
+
+/// Sizes of images for the list, with a few requests at a time
+fn image_sizes(images: &[RemoteImage]) -> Result<Vec<String>, Box<dyn Error>> {
+    let size = |image: &RemoteImage| match util::download_length(&image.url) {
+        Ok(Some(len)) => util::format_size(len),
+        Ok(None) => "unknown".to_string(),
+        Err(err) => {
+            log::warn!("failed to get size of {}: {err}", image.name);
+            "unknown".to_string()
+        }
+    };
+    let chunk_size = images.len().div_ceil(SIZE_REQUESTS).max(1);
+    let sizes = thread::scope(|scope| {
+        let handles: Vec<_> = images
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(size).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().map_err(|_| "size request thread panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    Ok(sizes.into_iter().flatten().collect())
+}
+
+/// Print the images published for all architectures
+pub fn list_remote(
+    cache_dir: &Path,
+    channel: Channel,
+    release: Option<&str>,
+    signing_key: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    for arch in Arch::ALL {
+        println!("{arch}:");
+        let remote = Remote {
+            arch,
+            channel,
+            release: release.map(str::to_string),
+            signing_key: signing_key.map(Path::to_path_buf),
+        };
+        let images = match remote_images(cache_dir, &remote) {
+            Ok(images) => images,
+            Err(err) => {
+                log::warn!("failed to list {arch} images: {err}");
+                continue;
+            }
+        };
+        let sizes = image_sizes(&images)?;
+        for (image, size) in images.iter().zip(sizes) {
+            let date = image.date().unwrap_or("unknown");
+            println!("  {:<64} {:>10} {}", image.name, size, date);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Directory for the harddrive, instead of the directory of the downloaded image
+    pub hd_dir: Option<PathBuf>,
+    /// Replace the harddrive when a newer image is available
+    pub update: bool,
+    /// Recreate the harddrive from the image
+    pub reset: bool,
+}
+
+impl Options {
+    fn hd_path(&self, image_dir: &Path, name: &str) -> io::Result<PathBuf> {
+        let hd_dir = self.hd_dir.as_deref().unwrap_or(image_dir);
+        fs::create_dir_all(hd_dir)?;
+        Ok(hd_dir.join(name))
+    }
+}
+
+/// Where the harddrive image comes from
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// Image published on the Redox server
+    Remote(Remote, Variant),
+    /// Image at a custom URL, with an optional expected hash
+    Url(String, Option<String>),
+    /// Local image file
+    Local(PathBuf),
+}
+
+impl Source {
+    /// Directory containing the downloaded or decompressed image, or None if used in place
+    fn image_dir(&self, cache_dir: &Path) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        match self {
+            // Each variant has its own directory so switching does not replace another harddrive
+            Source::Remote(remote, variant) => {
+                Ok(Some(remote.cache_dir(cache_dir).join(variant.name())))
+            }
+            // Each URL has its own directory, named after the hash of the URL
+            Source::Url(image_url, _) => {
+                let url_sha256 = util::sha256(&mut image_url.as_bytes())?;
+                Ok(Some(cache_dir.join("custom").join(url_sha256)))
+            }
+            // Each local image has its own directory, named after the hash of its path
+            Source::Local(image_path) => {
+                let image_path = local_path(image_path)?;
+                if image_path.extension() == Some(OsStr::new("zst")) {
+                    let path_sha256 = util::sha256(&mut image_path.as_os_str().as_encoded_bytes())?;
+                    Ok(Some(cache_dir.join("local").join(path_sha256)))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    /// Path of the harddrive, which may not have been created yet
+    pub fn harddrive_path(
+        &self,
+        cache_dir: &Path,
+        options: &Options,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        match (self, self.image_dir(cache_dir)?) {
+            (_, Some(image_dir)) => Ok(options
+                .hd_dir
+                .as_deref()
+                .unwrap_or(&image_dir)
+                .join("harddrive.img")),
+            // Raw and qcow2 images are used in place
+            (Source::Local(image_path), None) => local_path(image_path),
+            (_, None) => unreachable!("only local images are used in place"),
+        }
+    }
+
+    /// Download needed to prepare the harddrive, if the image is not cached
+    pub fn missing(
+        &self,
+        cache_dir: &Path,
+        options: &Options,
+    ) -> Result<Option<Missing>, Box<dyn Error>> {
+        let hd_path = self.harddrive_path(cache_dir, options)?;
+        let Some(image_dir) = self.image_dir(cache_dir)? else {
+            return Ok(None);
+        };
+        match self {
+            Source::Remote(remote, variant) => Ok(remote_missing(
+                cache_dir,
+                remote,
+                *variant,
+                "harddrive.img",
+                &image_dir,
+                &hd_path,
+                options,
+            )),
+            Source::Url(image_url, image_sha256) => {
+                if !options.reset && hd_path.is_file() {
+                    return Ok(None);
+                }
+                // Compressed images are kept, so the harddrive can be prepared again from them
+                let image_name = url_file_name(image_url)?;
+                let path = if image_name.ends_with(".zst") {
+                    if store::cached(cache_dir, image_url, image_sha256.as_deref()).is_some()
+                        || image_dir.join(image_name).is_file()
+                    {
+                        return Ok(None);
+                    }
+                    match image_sha256 {
+                        Some(image_sha256) => store::path(cache_dir, image_sha256),
+                        None => store::dir(cache_dir),
+                    }
+                } else {
+                    hd_path
+                };
+                Ok(Some(Missing {
+                    path,
+                    what: "image".to_string(),
+                    url: image_url.clone(),
+                }))
+            }
+            Source::Local(_) => Ok(None),
+        }
+    }
+
+    /// Download and decompress the harddrive image, if not already done
+    pub fn harddrive(
+        &self,
+        cache_dir: &Path,
+        options: &Options,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        let hd_path = self.harddrive_path(cache_dir, options)?;
+        let Some(image_dir) = self.image_dir(cache_dir)? else {
+            if options.reset {
+                return Err(format!("cannot reset {hd_path:?}, it is used in place").into());
+            }
+            return Ok(hd_path);
+        };
+        fs::create_dir_all(&image_dir)?;
+        if let Some(hd_dir) = hd_path.parent() {
+            fs::create_dir_all(hd_dir)?;
+        }
+
+        match self {
+            Source::Remote(remote, variant) => remote_file(
+                cache_dir,
+                remote,
+                *variant,
+                "harddrive.img",
+                &image_dir,
+                hd_path,
+                options,
+            ),
+            Source::Url(image_url, image_sha256) => {
+                if options.reset || !hd_path.is_file() {
+                    let image_name = url_file_name(image_url)?;
+                    prepare(
+                        cache_dir,
+                        &image_dir,
+                        None,
+                        image_url,
+                        image_name,
+                        image_sha256.as_deref(),
+                        &hd_path,
+                    )?;
+                }
+                Ok(hd_path)
+            }
+            Source::Local(image_path) => {
+                let image_path = local_path(image_path)?;
+                // Decompress again if the image was rebuilt since it was last decompressed
+                let outdated = match fs::metadata(&hd_path) {
+                    Ok(hd_metadata) => {
+                        fs::metadata(&image_path)?.modified()? > hd_metadata.modified()?
+                    }
+                    Err(_) => true,
+                };
+                if options.reset || outdated {
+                    output::status!("decompressing {}", image_path.display());
+                    let hd_partial = cache::partial_path(&hd_path);
+                    util::zstd_decompress_progress(&image_path, &hd_partial)?;
+                    cache::commit(&hd_partial, &hd_path)?;
+                }
+                Ok(hd_path)
+            }
+        }
+    }
+}
+
+/// Name of the file at an image URL
+fn url_file_name(image_url: &str) -> Result<&str, String> {
+    image_url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| format!("image URL {image_url:?} does not contain a file name"))
+}
+
+fn local_path(image_path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::canonicalize(image_path)
+        .map_err(|err| format!("failed to find image {image_path:?}: {err}").into())
+}
+
+/// Download and decompress the livedisk ISO, if not already done
+pub fn livedisk(
+    cache_dir: &Path,
+    remote: &Remote,
+    variant: Variant,
+    options: &Options,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let image_dir = remote.cache_dir(cache_dir).join(variant.name());
+    fs::create_dir_all(&image_dir)?;
+
+    // The livedisk is never written to, so it is shared by all profiles
+    let iso_path = image_dir.join("livedisk.iso");
+    remote_file(
+        cache_dir,
+        remote,
+        variant,
+        "livedisk.iso",
+        &image_dir,
+        iso_path,
+        options,
+    )
+}
+
+/// Download needed to prepare the livedisk ISO, if it is not cached
+pub fn livedisk_missing(
+    cache_dir: &Path,
+    remote: &Remote,
+    variant: Variant,
+    options: &Options,
+) -> Option<Missing> {
+    let image_dir = remote.cache_dir(cache_dir).join(variant.name());
+    let iso_path = image_dir.join("livedisk.iso");
+    remote_missing(
+        cache_dir,
+        remote,
+        variant,
+        "livedisk.iso",
+        &image_dir,
+        &iso_path,
+        options,
+    )
+}
+
+/// Create a blank harddrive for installing Redox from the livedisk, if not already done
+pub fn blank_harddrive(
+    cache_dir: &Path,
+    remote: &Remote,
+    variant: Variant,
+    size: u64,
+    options: &Options,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let image_dir = remote.cache_dir(cache_dir).join(variant.name());
+    let hd_path = options.hd_path(&image_dir, "target.img")?;
+    if options.reset || !hd_path.is_file() {
+        output::status!("creating {} blank harddrive", util::format_size(size));
+        let hd_partial = cache::partial_path(&hd_path);
+        fs::File::create(&hd_partial)?.set_len(size)?;
+        cache::commit(&hd_partial, &hd_path)?;
+    }
+    Ok(hd_path)
+}
+
+/// Download and decompress the latest image of a kind, such as harddrive.img, into path
+fn remote_file(
+    cache_dir: &Path,
+    remote: &Remote,
+    variant: Variant,
+    kind: &str,
+    image_dir: &Path,
+    hd_path: PathBuf,
+    options: &Options,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let source_path = hd_path.with_extension("sha256");
+    if options.reset && hd_path.is_file() {
+        if let Some((sha256, name)) = source_image(&hd_path) {
+            // Uses the cached compressed image, only downloading it if missing or corrupted
+            output::status!("resetting {kind} from {name}");
+            let url = format!("{}/{name}", remote.url());
+            prepare(
+                cache_dir,
+                image_dir,
+                Some(remote.arch),
+                &url,
+                &name,
+                Some(&sha256),
+                &hd_path,
+            )?;
+            return Ok(hd_path);
+        }
+        log::warn!("unknown source for {kind}, resetting from the latest image");
+    }
+
+    let image = if hd_path.is_file() && !options.reset {
+        let source = match fs::read_to_string(&source_path) {
+            Ok(source) => source,
+            Err(err) => {
+                log::info!("not checking for updates, failed to read {source_path:?}: {err}");
+                return Ok(hd_path);
+            }
+        };
+
+        if http::offline() {
+            log::info!("not checking for {kind} updates while offline");
+            return Ok(hd_path);
+        }
+
+        let image = match latest_image(cache_dir, remote, variant, kind) {
+            Ok(image) => image,
+            Err(err) => {
+                log::warn!("failed to check for image updates: {err}");
+                return Ok(hd_path);
+            }
+        };
+
+        if source.split_whitespace().next() == Some(&image.sha256) {
+            // Harddrive was created from the latest image
+            return Ok(hd_path);
+        }
+
+        output::status!("a newer {variant} {kind} is available: {}", image.name);
+        if !options.update
+            && !util::confirm(&format!(
+                "replace {kind} with it? changes made in the guest will be lost"
+            ))?
+        {
+            output::status!("using existing {kind}, run with --update to replace it");
+            return Ok(hd_path);
+        }
+
+        image
+    } else {
+        latest_image(cache_dir, remote, variant, kind)?
+    };
+
+    prepare(
+        cache_dir,
+        image_dir,
+        Some(remote.arch),
+        &image.url,
+        &image.name,
+        Some(&image.sha256),
+        &hd_path,
+    )?;
+
+    Ok(hd_path)
+}
+
+/// Hash and name of the compressed image that a harddrive was created from
+fn source_image(hd_path: &Path) -> Option<(String, String)> {
+    let source = fs::read_to_string(hd_path.with_extension("sha256")).ok()?;
+    let (sha256, name) = source.lines().next()?.split_once("  ")?;
+    Some((sha256.to_string(), name.to_string()))
+}
+
+/// Download needed by remote_file, if the image is not cached
+fn remote_missing(
+    cache_dir: &Path,
+    remote: &Remote,
+    variant: Variant,
+    kind: &str,
+    image_dir: &Path,
+    hd_path: &Path,
+    options: &Options,
+) -> Option<Missing> {
+    if hd_path.is_file() {
+        if !options.reset {
+            return None;
+        }
+        if let Some((sha256, name)) = source_image(hd_path) {
+            let path = store::path(cache_dir, &sha256);
+            let cached = path.is_file() || image_dir.join(&name).is_file();
+            return (!cached).then(|| Missing {
+                path,
+                what: format!("{variant} {kind} image {name}"),
+                url: format!("{}/{name}", remote.url()),
+            });
+        }
+    }
+    Some(Missing {
+        path: hd_path.to_path_buf(),
+        what: format!("latest {variant} {kind}, listed in SHA256SUM"),
+        url: format!("{}/SHA256SUM", remote.url()),
+    })
+}
+
+/// Find the most recent image of a kind for a location and variant
+fn latest_image(
+    cache_dir: &Path,
+    remote: &Remote,
+    variant: Variant,
+    kind: &str,
+) -> Result<RemoteImage, Box<dyn Error>> {
+    let arch = remote.arch;
+    let image_prefix = format!("redox_{variant}_{arch}_");
+    let image_suffix = format!("_{kind}.zst");
+    // Image names sort by build date and time
+    let image = remote_images(cache_dir, remote)?
+        .into_iter()
+        .filter(|image| {
+            image.name.starts_with(&image_prefix) && image.name.ends_with(&image_suffix)
+        })
+        .max_by(|a, b| a.name.cmp(&b.name))
+        .ok_or_else(|| format!("{variant} {kind} not found for {arch}"))?;
+    Ok(image)
+}
+
+/// Detect the QEMU format of a harddrive image
+pub fn format(hd_path: &Path) -> io::Result<&'static str> {
+    let mut magic = [0; 4];
+    let mut file = fs::File::open(hd_path)?;
+    match file.read_exact(&mut magic) {
+        Ok(()) if &magic == b"QFI\xfb" => Ok("qcow2"),
+        Ok(()) => Ok("raw"),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok("raw"),
+        Err(err) => Err(err),
+    }
+}
+
+/// Convert a raw harddrive image to qcow2 in place, using qemu-img
+pub fn convert_qcow2(hd_path: &Path) -> Result<(), Box<dyn Error>> {
+    let qemu_img = util::which("qemu-img").ok_or("qemu-img not found, please install QEMU")?;
+    output::status!("converting {} to qcow2", hd_path.display());
+    let hd_partial = cache::partial_path(hd_path);
+    let status = Command::new(qemu_img)
+        .arg("convert")
+        .arg("-p")
+        .arg("-f")
+        .arg("raw")
+        .arg("-O")
+        .arg("qcow2")
+        .arg(hd_path)
+        .arg(&hd_partial)
+        .status()?;
+    if !status.success() {
+        return Err(format!("qemu-img convert failed with {status}").into());
+    }
+    cache::commit(&hd_partial, hd_path)?;
+    Ok(())
+}
+
+/// File marking a harddrive that was in use when its VM was killed
+pub fn dirty_path(hd_path: &Path) -> PathBuf {
+    hd_path.with_extension("dirty")
+}
+
+/// Mark a harddrive as dirty, so it is checked before it is used again
+pub fn mark_dirty(hd_path: &Path) -> io::Result<()> {
+    cache::write(&dirty_path(hd_path), "")
+}
+
+/// Check a dirty harddrive, repairing a qcow2 harddrive with qemu-img. A raw harddrive has
+/// no metadata of its own, so only the file system of the guest may need to be checked
+pub fn repair_dirty(hd_path: &Path) -> Result<(), Box<dyn Error>> {
+    let dirty_path = dirty_path(hd_path);
+    if !dirty_path.is_file() {
+        return Ok(());
+    }
+    if format(hd_path)? == "qcow2" {
+        let qemu_img = util::which("qemu-img").ok_or("qemu-img not found, please install QEMU")?;
+        output::status!("repairing {} after its VM was killed", hd_path.display());
+        let status = Command::new(qemu_img)
+            .arg("check")
+            .arg("-r")
+            .arg("all")
+            .arg("-f")
+            .arg("qcow2")
+            .arg(hd_path)
+            .status()?;
+        // Exits with 3 when only leaked clusters remain, which waste space but are harmless
+        if !matches!(status.code(), Some(0 | 3)) {
+            return Err(format!("qemu-img check failed with {status}").into());
+        }
+    } else {
+        log::warn!(
+            "{} was in use when its VM was killed, the guest may need to check its file system",
+            hd_path.display()
+        );
+    }
+    fs::remove_file(&dirty_path)?;
+    Ok(())
+}
+
+/// Cached compressed image the existing harddrive was created from
+fn previous_image(cache_dir: &Path, image_dir: &Path, hd_path: &Path) -> Option<PathBuf> {
+    let (sha256, name) = source_image(hd_path)?;
+    [store::path(cache_dir, &sha256), image_dir.join(name)]
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+/// Try to download only the parts of an image that differ from the previous image, which
+/// is verified afterwards like any other download
+fn delta_download(image_url: &str, old_path: &Path, image_path: &Path) {
+    // Kept apart from the partial file of a full download, which is continued from its end
+    let partial = image_path.with_extension("delta.partial");
+    match util::download_delta(image_url, old_path, &partial) {
+        Ok(true) => match cache::commit(&partial, image_path) {
+            Ok(()) => return,
+            Err(err) => log::warn!("failed to rename {partial:?}: {err}"),
+        },
+        Ok(false) => log::info!("no block index for {image_url}, downloading the whole image"),
+        Err(err) => log::warn!("delta download failed, downloading the whole image: {err}"),
+    }
+    let _ = fs::remove_file(&partial);
+}
+
+/// Download, verify, and decompress an image into hd_path
+///
+/// The hashes of the image and of the new harddrive are recorded next to the harddrive, in
+/// SHA256SUM format, so the harddrive can be checked for updates, reset, and verified
+fn prepare(
+    cache_dir: &Path,
+    image_dir: &Path,
+    arch: Option<Arch>,
+    image_url: &str,
+    image_name: &str,
+    image_sha256: Option<&str>,
+    hd_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let compressed = image_name.ends_with(".zst");
+
+    output::status!("downloading {image_name}");
+    let (image_path, path_sha256) = if compressed {
+        // Compressed images are kept in the store. Images cached before the store was used are
+        // moved into it, and then only the parts that changed since the previous image are
+        // downloaded
+        let legacy = image_dir.join(image_name);
+        if let Some(image_sha256) = image_sha256 {
+            let image_path = store::path(cache_dir, image_sha256);
+            store::adopt(&legacy, &image_path)?;
+            if !image_path.exists() && http::torrent() {
+                torrent::download(image_url, &image_path);
+            }
+            if !image_path.exists() {
+                if let Some(old_path) = previous_image(cache_dir, image_dir, hd_path) {
+                    delta_download(image_url, &old_path, &image_path);
+                }
+            }
+        }
+        let entry = store::Entry {
+            kind: "image".to_string(),
+            name: image_name.to_string(),
+            arch: arch.map(|arch| arch.name().to_string()),
+            version: date(image_name).map(str::to_string),
+            url: image_url.to_string(),
+            sha256: image_sha256.map(str::to_string),
+        };
+        store::fetch(cache_dir, entry, &legacy)?
+    } else {
+        // Uncompressed images are downloaded directly to the harddrive
+        let image_path = cache::partial_path(hd_path);
+        let path_sha256 = match image_sha256 {
+            Some(image_sha256) => {
+                util::sha256_or_download(image_url, image_sha256, &image_path)?;
+                image_sha256.to_string()
+            }
+            None => util::download_progress(image_url, &image_path)?,
+        };
+        (image_path, path_sha256)
+    };
+    if image_sha256.is_none() {
+        log::warn!("{image_name} was not verified, it has hash {path_sha256:?}");
+    }
+    let image_sha256 = path_sha256;
+
+    let hd_partial = cache::partial_path(hd_path);
+    let hd_sha256 = if compressed {
+        util::zstd_decompress_progress(&image_path, &hd_partial)?
+    } else {
+        image_sha256.clone()
+    };
+    cache::commit(&hd_partial, hd_path)?;
+
+    let hd_name = hd_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    cache::write(
+        &hd_path.with_extension("sha256"),
+        format!("{image_sha256}  {image_name}\n{hd_sha256}  {hd_name}\n"),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_shasum_lines() {
+        let hash = "0123456789abcdef".repeat(4);
+        let shasum = format!(
+            "{hash}  redox_demo_x86_64_2024-09-07_1225_harddrive.img.zst\n\
+             {hash} *redox_server_x86_64_2024-09-07_1225_livedisk.iso.zst\n"
+        );
+        let images = parse_shasum("https://example.com/img/x86_64", &shasum);
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].sha256, hash);
+        assert_eq!(
+            images[0].url,
+            "https://example.com/img/x86_64/redox_demo_x86_64_2024-09-07_1225_harddrive.img.zst"
+        );
+        assert_eq!(images[0].date(), Some("2024-09-07"));
+        assert_eq!(
+            images[1].name,
+            "redox_server_x86_64_2024-09-07_1225_livedisk.iso.zst"
+        );
+    }
+
+    #[test]
+    fn parse_shasum_skips_malformed_lines() {
+        let hash = "0123456789abcdef".repeat(4);
+        let shasum = format!(
+            "\n\
+             short\n\
+             {hash}\n\
+             ééééééééééééééééééééééééééééééééééé  name.img\n\
+             {}  name.img\n\
+             {hash}  two names\n",
+            "x".repeat(64)
+        );
+        assert!(parse_shasum("https://example.com", &shasum).is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::cache;
+use crate::image;
+use crate::output;
+use crate::profile::Profile;
+use crate::util;
+
+/// Record of a VM launched by rebox, kept in a state file while it runs
+pub struct Instance {
+    /// Name of the profile, or default
+    pub name: String,
+    /// Process ID of QEMU
+    pub pid: u32,
+    /// Launch time in seconds since the Unix epoch
+    pub started: u64,
+    /// Ports forwarded from the host, like 8080:80
+    pub ports: Vec<String>,
+    /// Harddrive in use, if there is one
+    pub disk: Option<PathBuf>,
+}
+
+/// Path of the state file of the VM for a profile, or the VM without a profile
+pub fn state_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "json")
+}
+
+/// Path of the file QEMU writes its process ID to
+pub fn pid_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "pid")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+impl Instance {
+    /// Record of a VM started now by a process
+    pub fn new(
+        profile: Option<&Profile>,
+        pid: u32,
+        ports: Vec<String>,
+        disk: Option<PathBuf>,
+    ) -> Self {
+        Instance {
+            name: profile
+                .map_or("default", |profile| profile.name.as_str())
+                .to_string(),
+            pid,
+            started: now(),
+            ports,
+            disk,
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let state = json!({
+            "name": self.name,
+            "pid": self.pid,
+            "started": self.started,
+            "ports": self.ports,
+            "disk": self.disk,
+        });
+        cache::write(path, state.to_string())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let invalid = || format!("invalid state file {}", path.display());
+        let data = fs::read_to_string(path)
+            .map_err(|err| format!("failed to read {}: {err}", path.display()))?;
+        let state: Value = serde_json::from_str(&data).map_err(|_| invalid())?;
+        Ok(Instance {
+            name: state["name"].as_str().ok_or_else(invalid)?.to_string(),
+            pid: state["pid"]
+                .as_u64()
+                .and_then(|pid| pid.try_into().ok())
+                .ok_or_else(invalid)?,
+            started: state["started"].as_u64().ok_or_else(invalid)?,
+            ports: state["ports"]
+                .as_array()
+                .map(|ports| {
+                    ports
+                        .iter()
+                        .filter_map(|port| Some(port.as_str()?.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            disk: state["disk"].as_str().map(PathBuf::from),
+        })
+    }
+
+    /// Check if the process of the VM still exists and is the QEMU that was launched with
+    /// pid_path as its pidfile, as the process ID may belong to another process after a crash
+    pub fn is_running(&self, pid_path: &Path) -> Result<bool, String> {
+        let command_line = if cfg!(target_os = "linux") {
+            fs::read(
+                Path::new("/proc")
+                    .join(self.pid.to_string())
+                    .join("cmdline"),
+            )
+            .ok()
+            .map(|data| String::from_utf8_lossy(&data).replace('\0', " "))
+        } else if cfg!(unix) {
+            Command::new("ps")
+                .arg("-o")
+                .arg("command=")
+                .arg("-p")
+                .arg(self.pid.to_string())
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        } else {
+            return Err("checking if a VM is running is only supported on Unix".to_string());
+        };
+        Ok(command_line.is_some_and(|command_line| {
+            command_line.contains(&format!("-pidfile {}", pid_path.display()))
+        }))
+    }
+}
+
+/// Ask a process to terminate, or force it to
+fn signal(pid: u32, force: bool) -> io::Result<()> {
+    let mut command = Command::new("kill");
+    if force {
+        command.arg("-KILL");
+    }
+    command.arg(pid.to_string());
+    command.stderr(Stdio::null()).status()?;
+    Ok(())
+}
+
+/// Terminate the VM with a name, then remove its runtime files, which also cleans up after a
+/// VM that crashed
+pub fn kill(cache_dir: &Path, name: &str) -> Result<(), Box<dyn Error>> {
+    let run_dir = cache_dir.join("run");
+    let state_path = run_dir.join(format!("{name}.json"));
+    if !state_path.is_file() {
+        return Err(format!("no VM named {name:?} was launched by rebox").into());
+    }
+    let instance = Instance::load(&state_path)?;
+    let pid_path = state_path.with_extension("pid");
+    if instance.is_running(&pid_path)? {
+        output::status!("terminating {name} (PID {})", instance.pid);
+        signal(instance.pid, false)?;
+        // QEMU exits quickly on SIGTERM unless it is stuck
+        for _ in 0..50 {
+            if !instance.is_running(&pid_path)? {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        if instance.is_running(&pid_path)? {
+            log::warn!("{name} did not terminate, killing it");
+            signal(instance.pid, true)?;
+            // QEMU had no chance to flush the harddrive, so it is checked before the next run
+            if let Some(disk) = &instance.disk {
+                image::mark_dirty(disk)?;
+            }
+        }
+        if let Some(disk) = &instance.disk {
+            log::warn!(
+                "{} was in use and may not have been cleanly unmounted by the guest",
+                disk.display()
+            );
+        }
+    } else {
+        output::status!("{name} is not running, cleaning up");
+    }
+
+    // Sockets and files of the run, except the host keys which are kept between runs
+    let prefix = format!("{name}.");
+    let mut removed = 0;
+    for entry in fs::read_dir(&run_dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if file_name.starts_with(&prefix) && !file_name.ends_with(".known_hosts") {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    output::status!("removed {removed} runtime files of {name}");
+    Ok(())
+}
+
+/// State files of all VMs with their records, including ones that are no longer running
+pub fn all(cache_dir: &Path) -> io::Result<Vec<(PathBuf, Instance)>> {
+    let mut instances = Vec::new();
+    let entries = match fs::read_dir(cache_dir.join("run")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(instances),
+        Err(err) => return Err(err),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            match Instance::load(&path) {
+                Ok(instance) => instances.push((path, instance)),
+                Err(err) => log::warn!("{err}"),
+            }
+        }
+    }
+    instances.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+    Ok(instances)
+}
+
+/// Print the VMs launched by rebox that are running
+pub fn ps(cache_dir: &Path) -> Result<(), Box<dyn Error>> {
+    println!(
+        "{:<16} {:>8} {:>8}  {:<24} DISK",
+        "NAME", "PID", "UPTIME", "PORTS"
+    );
+    for (state_path, instance) in all(cache_dir)? {
+        if !instance.is_running(&state_path.with_extension("pid"))? {
+            continue;
+        }
+        let ports = if instance.ports.is_empty() {
+            "-".to_string()
+        } else {
+            instance.ports.join(",")
+        };
+        let disk = instance
+            .disk
+            .as_ref()
+            .map_or("-".to_string(), |disk| disk.display().to_string());
+        println!(
+            "{:<16} {:>8} {:>8}  {:<24} {disk}",
+            instance.name,
+            instance.pid,
+            util::format_duration(now().saturating_sub(instance.started)),
+            ports
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::{error::Error, path::Path, thread, time::Duration};
+
+use serde_json::{json, Value};
+
+use crate::qmp;
+
+/// Time between keys when typing, so the guest does not miss any
+const TYPE_DELAY: Duration = Duration::from_millis(20);
+
+/// QEMU key code for a key name, accepting some common names besides the QEMU ones
+fn qcode(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "control" => "ctrl",
+        "enter" | "return" => "ret",
+        "space" => "spc",
+        "escape" => "esc",
+        "del" => "delete",
+        "ins" => "insert",
+        "pageup" => "pgup",
+        "pagedown" => "pgdn",
+        "super" | "win" | "meta" => "meta_l",
+        _ => return name,
+    }
+    .to_string()
+}
+
+/// Key code and whether shift is needed to type a character, on a US keyboard layout
+fn char_qcode(c: char) -> Option<(String, bool)> {
+    if c.is_ascii_alphanumeric() {
+        return Some((c.to_ascii_lowercase().to_string(), c.is_ascii_uppercase()));
+    }
+    let (qcode, shift) = match c {
+        ' ' => ("spc", false),
+        '\n' => ("ret", false),
+        '\t' => ("tab", false),
+        '-' => ("minus", false),
+        '=' => ("equal", false),
+        '[' => ("bracket_left", false),
+        ']' => ("bracket_right", false),
+        '\\' => ("backslash", false),
+        ';' => ("semicolon", false),
+        '\'' => ("apostrophe", false),
+        '`' => ("grave_accent", false),
+        ',' => ("comma", false),
+        '.' => ("dot", false),
+        '/' => ("slash", false),
+        '!' => ("1", true),
+        '@' => ("2", true),
+        '#' => ("3", true),
+        '$' => ("4", true),
+        '%' => ("5", true),
+        '^' => ("6", true),
+        '&' => ("7", true),
+        '*' => ("8", true),
+        '(' => ("9", true),
+        ')' => ("0", true),
+        '_' => ("minus", true),
+        '+' => ("equal", true),
+        '{' => ("bracket_left", true),
+        '}' => ("bracket_right", true),
+        '|' => ("backslash", true),
+        ':' => ("semicolon", true),
+        '"' => ("apostrophe", true),
+        '~' => ("grave_accent", true),
+        '<' => ("comma", true),
+        '>' => ("dot", true),
+        '?' => ("slash", true),
+        _ => return None,
+    };
+    Some((qcode.to_string(), shift))
+}
+
+fn key_event(qcode: &str, down: bool) -> Value {
+    json!({
+        "type": "key",
+        "data": { "down": down, "key": { "type": "qcode", "data": qcode } },
+    })
+}
+
+/// Press keys together, then release them in reverse order
+fn press(client: &mut qmp::Client, qcodes: &[String]) -> Result<(), String> {
+    let events: Vec<Value> = qcodes
+        .iter()
+        .map(|qcode| key_event(qcode, true))
+        .chain(qcodes.iter().rev().map(|qcode| key_event(qcode, false)))
+        .collect();
+    client.input_send_event(events)
+}
+
+/// Press a combination of keys in the running VM, like ctrl-alt-f2
+pub fn sendkey(qmp_path: &Path, combination: &str) -> Result<(), Box<dyn Error>> {
+    let qcodes: Vec<String> = combination.split('-').map(qcode).collect();
+    if qcodes.iter().any(String::is_empty) {
+        return Err(format!("invalid key combination {combination:?}").into());
+    }
+    let mut client = qmp::Client::connect(qmp_path)?;
+    press(&mut client, &qcodes)?;
+    Ok(())
+}
+
+/// Type text in the running VM, one key at a time
+pub fn type_text(qmp_path: &Path, text: &str) -> Result<(), Box<dyn Error>> {
+    // Check all characters first, so nothing is typed if any cannot be
+    let keys = text
+        .chars()
+        .map(|c| char_qcode(c).ok_or_else(|| format!("cannot type character {c:?}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut client = qmp::Client::connect(qmp_path)?;
+    for (qcode, shift) in keys {
+        if shift {
+            press(&mut client, &["shift".to_string(), qcode])?;
+        } else {
+            press(&mut client, &[qcode])?;
+        }
+        thread::sleep(TYPE_DELAY);
+    }
+    Ok(())
+}
@@ -1,128 +1,875 @@
-use qemu::QEMU_X86_64_SOFTMMU;
-use std::{env, error::Error, fs, process::Command};
+use std::{
+    error::Error,
+    fs, mem,
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+};
 
+use serde_json::json;
+
+use crate::accel::Accel;
+use crate::arch::Machine;
+use crate::args::{Args, CacheCommand, CleanOptions, SnapshotCommand, Subcommand};
+use crate::disk::DiskInterface;
+use crate::image::{Remote, Source};
+use crate::net::{MacAddr, Nic, NicModel};
+use crate::profile::Profile;
+use crate::serial::Serial;
+
+mod accel;
+mod agent;
+mod arch;
+mod args;
+mod audio;
+mod audit;
+mod build;
+mod bundle;
+mod cache;
+mod clean;
+mod config;
+mod console;
+mod disk;
+mod display;
+mod gdb;
+mod gpu;
+mod host;
+mod http;
+mod image;
+mod instance;
+mod keys;
+mod net;
+mod offline;
+mod output;
+mod pcap;
+mod profile;
 mod progress_bar;
+mod qemu;
+mod qmp;
+mod record;
+mod resize;
+mod rtc;
+mod screenshot;
+mod serial;
+mod share;
+mod snapshot;
+mod ssh;
+mod stop;
+mod store;
+mod torrent;
+mod tpm;
+mod uefi;
+mod usb;
 mod util;
+mod verify;
+mod vnc;
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
-    let cache_dir = dirs::cache_dir()
-        .ok_or("user cache directory not found")?
-        .join("rebox");
-    println!("using cache directory {cache_dir:?}");
-    fs::create_dir_all(&cache_dir)?;
+    let args = Args::parse()?;
+    http::configure(http::Settings {
+        retries: args.retries.unwrap_or(http::DEFAULT_RETRIES),
+        mirrors: args
+            .mirrors
+            .iter()
+            .map(|url| (image::SERVER, url))
+            .chain(args.qemu_mirrors.iter().map(|url| (qemu::SERVER, url)))
+            .map(|(server, url)| http::Mirror {
+                server,
+                url: url.clone(),
+            })
+            .collect(),
+        offline: args.offline,
+        connections: args.connections.unwrap_or(http::DEFAULT_CONNECTIONS),
+        proxy: args.proxy.as_deref().map(reqwest::Proxy::all).transpose()?,
+        limit_rate: args.limit_rate,
+        torrent: args.torrent,
+    });
+    let res = command(&args);
+    if let Err(err) = &res {
+        if output::json() {
+            output::event("error", json!({ "message": err.to_string() }));
+            process::exit(1);
+        }
+    }
+    res
+}
 
-    //TODO: allow recreating harddrive
-    let hd_path = cache_dir.join("harddrive.img");
-    if !hd_path.is_file() {
-        let img_url = "https://static.redox-os.org/img/x86_64";
-        let shasum_url = format!("{img_url}/SHA256SUM");
-        let shasum = reqwest::blocking::get(shasum_url)?.text()?;
-        let mut image_opt = None;
-        for line in shasum.lines() {
-            let sha256 = &line[..64];
-            let name = &line[66..];
-            if name.starts_with("redox_demo_x86_64_") && name.ends_with("_harddrive.img.zst") {
-                image_opt = Some((name.to_string(), sha256.to_string()));
-            }
+/// Run the subcommand
+fn command(args: &Args) -> Result<(), Box<dyn Error>> {
+    match &args.subcommand {
+        Subcommand::Run { .. } => run(args, true),
+        Subcommand::Download => run(args, false),
+        Subcommand::Images => image::list_remote(
+            &cache_dir(args)?,
+            args.channel,
+            args.release.as_deref(),
+            args.signing_key.as_deref(),
+        ),
+        Subcommand::Snapshot { command } => snapshot(args, command),
+        Subcommand::Clean(options)
+        | Subcommand::Cache {
+            command: CacheCommand::Clean(options),
+        } => clean(args, options),
+        Subcommand::Cache {
+            command: CacheCommand::Info,
+        } => cache::info(&cache_dir(args)?),
+        Subcommand::Resize { size } => resize(args, *size),
+        Subcommand::CpuModels => cpu_models(args),
+        Subcommand::Balloon { size } => balloon(args, *size),
+        Subcommand::Record { path, fps } => record::run(
+            &qmp::socket_path(&cache_dir(args)?, args.profile.as_ref()),
+            path,
+            *fps,
+        ),
+        Subcommand::Qmp { command, arguments } => Ok(qmp::run(
+            &qmp::socket_path(&cache_dir(args)?, args.profile.as_ref()),
+            command,
+            arguments.as_deref(),
+        )?),
+        Subcommand::Stop { timeout } => Ok(stop::run(
+            &qmp::socket_path(&cache_dir(args)?, args.profile.as_ref()),
+            timeout.unwrap_or(stop::DEFAULT_TIMEOUT),
+        )?),
+        Subcommand::Pause => pause(args, true),
+        Subcommand::Resume => pause(args, false),
+        Subcommand::Save { name } => state(args, name, false),
+        Subcommand::Restore { name } => state(args, name, true),
+        Subcommand::SendKey { keys } => keys::sendkey(
+            &qmp::socket_path(&cache_dir(args)?, args.profile.as_ref()),
+            keys,
+        ),
+        Subcommand::Type { text } => keys::type_text(
+            &qmp::socket_path(&cache_dir(args)?, args.profile.as_ref()),
+            text,
+        ),
+        Subcommand::Ps => instance::ps(&cache_dir(args)?),
+        Subcommand::Kill { name } => {
+            let name = name
+                .as_deref()
+                .or(args.profile.as_ref().map(|p| p.name.as_str()));
+            instance::kill(&cache_dir(args)?, name.unwrap_or("default"))
+        }
+        Subcommand::Attach { monitor } => {
+            let cache_dir = cache_dir(args)?;
+            let path = if *monitor {
+                console::monitor_path(&cache_dir, args.profile.as_ref())
+            } else {
+                serial::socket_path(&cache_dir, args.profile.as_ref())
+            };
+            console::attach(&path)
+        }
+        Subcommand::Gdb { kernel } => gdb::run(
+            args.arch,
+            args.gdb.unwrap_or(gdb::DEFAULT_PORT),
+            kernel.as_deref(),
+            args.image.as_deref(),
+        ),
+        Subcommand::Screenshot { path } => screenshot::run(
+            &qmp::socket_path(&cache_dir(args)?, args.profile.as_ref()),
+            &path.clone().unwrap_or_else(screenshot::default_path),
+        ),
+        Subcommand::Agent => agent::attach(&agent::socket_path(
+            &cache_dir(args)?,
+            args.profile.as_ref(),
+        )),
+        Subcommand::Ssh {
+            user,
+            args: ssh_args,
+        } => ssh::run(&cache_dir(args)?, args.profile.as_ref(), user, ssh_args),
+        Subcommand::Export { path } => {
+            let profile = args.profile.as_ref().ok_or("export requires --profile")?;
+            bundle::export(profile, &cache_dir(args)?, path)
         }
+        Subcommand::Build { source_dir } => {
+            let profile = args.profile.as_ref().ok_or("build requires --profile")?;
+            build::run(source_dir, args.arch, args.variant, profile)
+        }
+        Subcommand::Import { path } => {
+            bundle::import(path, &cache_dir(args)?, args.profile.as_ref())
+        }
+        Subcommand::Completions { shell } => {
+            args::completions(*shell);
+            Ok(())
+        }
+        Subcommand::Verify { repair } => {
+            let cache_dir = cache_dir(args)?;
+            let _lock = cache::Lock::acquire(&cache_dir)?;
+            audit::run(&cache_dir, *repair)
+        }
+    }
+}
+
+/// Prepare everything needed to run the VM, and run it if launch is true
+fn run(args: &Args, launch: bool) -> Result<(), Box<dyn Error>> {
+    let arch = args.arch;
+
+    let cache_dir = cache_dir(args)?;
+    output::status!("using cache directory {cache_dir:?}");
 
-        let (image_name, image_sha256) = image_opt.ok_or("demo harddrive image not found")?;
-        println!("downloading {image_name}");
-        let image_url = format!("{img_url}/{image_name}");
-        let image_path = cache_dir.join(image_name);
-        util::sha256_or_download(&image_url, &image_sha256, &image_path)?;
+    let machine = args.machine.unwrap_or(arch.default_machine());
+    if !arch.supports(machine) {
+        return Err(format!("{machine} machine is not supported for {arch}").into());
+    }
+    let disk_if = args.disk_if.unwrap_or(DiskInterface::default_for(machine));
+    disk_if.check(machine)?;
 
-        let hd_partial = cache_dir.join("harddrive.partial");
-        util::zstd_decompress_progress(&image_path, &hd_partial)?;
-        fs::rename(&hd_partial, &hd_path)?;
+    let image_options = image_options(args, &cache_dir);
+    let remote = Remote {
+        arch,
+        channel: args.channel,
+        release: args.release.clone(),
+        signing_key: args.signing_key.clone(),
+    };
+    if args.offline {
+        let missing = if args.livedisk {
+            image::livedisk_missing(&cache_dir, &remote, args.variant, &image_options)
+        } else {
+            image_source(args).missing(&cache_dir, &image_options)?
+        };
+        let qemu_missing = qemu::missing(
+            arch,
+            machine,
+            args.qemu,
+            args.qemu_version.as_deref(),
+            &cache_dir,
+            args.uefi && !args.secure_boot,
+        )?;
+        offline::check(&missing.into_iter().chain(qemu_missing).collect::<Vec<_>>())?;
     }
 
-    let qemu_url = "https://download.qemu.org/qemu-9.0.1.tar.xz";
-    let qemu_sha256 = "d0f4db0fbd151c0cf16f84aeb2a500f6e95009732546f44dafab8d2049bbb805";
-    //TODO: use sha256 to ensure directory is re-extracted as needed?
-    let qemu_dir = cache_dir.join(format!("qemu"));
-    if !qemu_dir.is_dir() {
-        println!("downloading QEMU source");
-        let qemu_tar_xz = cache_dir.join("qemu.tar.xz");
-        util::sha256_or_download(qemu_url, qemu_sha256, &qemu_tar_xz)?;
+    // Held until the images, QEMU, and firmware are prepared
+    let cache_lock = cache::Lock::acquire(&cache_dir)?;
+    let mut livedisk_path = None;
+    let hd_path = if args.livedisk {
+        livedisk_path = Some(image::livedisk(
+            &cache_dir,
+            &remote,
+            args.variant,
+            &image_options,
+        )?);
+        match args.blank_disk {
+            Some(size) => Some(image::blank_harddrive(
+                &cache_dir,
+                &remote,
+                args.variant,
+                size,
+                &image_options,
+            )?),
+            None => None,
+        }
+    } else {
+        Some(image_source(args).harddrive(&cache_dir, &image_options)?)
+    };
 
-        println!("extracting QEMU source");
-        let qemu_partial = cache_dir.join(format!("qemu.partial"));
-        if qemu_partial.is_dir() {
-            //TODO: race conditions, use lockfile on cache directory
-            fs::remove_dir_all(&qemu_partial)?;
+    let mut command = qemu::command(
+        arch,
+        machine,
+        args.qemu,
+        args.qemu_version.as_deref(),
+        &cache_dir,
+    )?;
+
+    // Set window name
+    match &args.profile {
+        Some(profile) => command
+            .arg("-name")
+            .arg(format!("Redox OS {arch} ({})", profile.name)),
+        None => command.arg("-name").arg(format!("Redox OS {arch}")),
+    };
+
+    let accel = match args.accel {
+        Some(Accel::Kvm) => {
+            accel::check_kvm_access()?;
+            Accel::Kvm
         }
-        util::extract_progress(&qemu_tar_xz, &qemu_partial)?;
-        fs::rename(&qemu_partial, &qemu_dir)?;
+        Some(accel) => accel,
+        None => Accel::detect(arch, command.get_program(), args.no_kvm)?,
+    };
+    let cpu_flag = if args.nested {
+        Some(accel::nested_flag(accel, arch)?)
+    } else {
+        None
+    };
+    accel.args(&mut command, arch, args.cpu_model.as_deref(), cpu_flag);
+
+    if args.secure_boot {
+        // Secure Boot firmware requires SMM to protect its variables
+        command
+            .arg("-machine")
+            .arg(format!("{},smm=on", machine.arg()));
+    } else {
+        command.arg("-machine").arg(machine.arg());
     }
 
-    let qemu_system_x86_64 = cache_dir.join("qemu-system-x86_64");
-    if !qemu_system_x86_64.is_file() {
-        println!("extracting QEMU binary");
-        let qemu_system_x86_64_partial = cache_dir.join("qemu-system-x86_64");
-        fs::write(&qemu_system_x86_64_partial, QEMU_X86_64_SOFTMMU)?;
+    if args.uefi || args.secure_boot {
+        uefi::args(
+            &mut command,
+            arch,
+            args.secure_boot,
+            args.qemu_version.as_deref(),
+            &cache_dir,
+            args.profile.as_ref(),
+        )?;
+    } else if let Some(firmware) = arch.firmware() {
+        // Load UEFI firmware on architectures without a legacy BIOS
+        command.arg("-bios").arg(firmware);
+    }
+    if args.auto_clean {
+        clean::auto(&cache_dir)?;
+    }
+    drop(cache_lock);
+    if !launch {
+        output::status!("everything needed to run is downloaded and prepared");
+        return Ok(());
+    }
 
-        #[cfg(unix)]
-        {
-            println!("marking QEMU binary as read-only and executable");
-            use std::os::unix::fs::PermissionsExt;
-            fs::set_permissions(
-                &qemu_system_x86_64_partial,
-                fs::Permissions::from_mode(0o555),
-            )?;
+    let memory = match args.memory {
+        Some(memory) => memory,
+        None => default_memory(),
+    };
+    command.arg("-m").arg(format!("{}M", memory >> 20));
+
+    let cpus = args.cpus.unwrap_or_else(default_cpus);
+    command.arg("-smp").arg(cpus.to_string());
+
+    args.rtc.args(&mut command);
+
+    // Serial output, with the console on stdio optionally logged
+    // A detached VM always logs the serial console, as there is no terminal to show it
+    let detached_serial = args.detach && args.serial.is_empty();
+    let serial_log = match &args.serial_log {
+        Some(Some(path)) => Some(path.clone()),
+        Some(None) => Some(serial::log_path(&cache_dir, args.profile.as_ref())),
+        None if detached_serial => Some(serial::log_path(&cache_dir, args.profile.as_ref())),
+        None => None,
+    };
+    if let Some(serial_log) = &serial_log {
+        if let Some(parent) = serial_log.parent() {
+            fs::create_dir_all(parent)?;
         }
+        output::status!("logging serial console to {}", serial_log.display());
+    }
+    if args.console {
+        console::args(&mut command, serial_log.as_deref());
+    } else if args.serial.is_empty() {
+        match &serial_log {
+            Some(serial_log) if detached_serial => {
+                let socket_path = serial::socket_path(&cache_dir, args.profile.as_ref());
+                serial::socket_args(&mut command, &socket_path, serial_log)?;
+            }
+            Some(serial_log) => serial::stdio_args(&mut command, serial_log),
+            None => {
+                command.arg("-serial").arg("stdio");
+            }
+        }
+    }
+    for serial in &args.serial {
+        match (serial, &serial_log) {
+            (Serial::Stdio, Some(serial_log)) => serial::stdio_args(&mut command, serial_log),
+            _ => {
+                command.arg("-serial").arg(serial.arg());
+            }
+        }
+    }
 
-        fs::rename(&qemu_system_x86_64_partial, &qemu_system_x86_64)?;
+    // Audio is not needed without a display
+    if !args.no_audio && !args.headless {
+        if args.audio.is_some() && !arch.has_audio(machine) {
+            log::warn!("ignoring --audio, there is no audio device for {arch} with {machine}");
+        }
+        arch.audio(&mut command, machine, args.audio);
+    }
+    // The window is sized to the guest display, so its size is set with the resolution
+    let resolution = args.resolution.or(args.window_size);
+    arch.gpu(&mut command, args.gpu, args.vga, resolution);
+    // No window is opened when showing the guest over VNC
+    let headless = args.headless || args.vnc.is_some();
+    let qemu_system = command.get_program().to_owned();
+    if let Some(display) = display::arg(
+        args.display,
+        args.gpu,
+        headless,
+        args.zoom_to_fit,
+        &qemu_system,
+    ) {
+        command.arg("-display").arg(display);
     }
+    if let Some(scale) = args.scale {
+        display::scale(&mut command, args.display, scale);
+    }
+    if args.fullscreen {
+        command.arg("-full-screen");
+    }
+    if let Some(vnc_display) = args.vnc {
+        let listen = args.vnc_listen.as_deref().unwrap_or("127.0.0.1");
+        let password_path = Profile::runtime_path(&cache_dir, args.profile.as_ref(), "vncpass");
+        let password_path = args.vnc_password.then_some(password_path.as_path());
+        vnc::args(&mut command, vnc_display, listen, password_path);
+        output::status!(
+            "VNC server listening on {listen}:{}",
+            5900 + u32::from(vnc_display)
+        );
+        // Not written by a dry run, which would replace the password of a running VM
+        if let Some(password_path) = password_path.filter(|_| !args.dry_run) {
+            let password = vnc::generate_password()?;
+            vnc::write_password(password_path, &password)?;
+            output::status!("VNC password is {password}");
+        }
+    }
+    arch.devices(&mut command);
+    // Devices on microvm use the PCIe bus enabled by Machine::arg
+    net::args(&mut command, &net_options(args, machine))?;
 
-    let mut command = Command::new(qemu_system_x86_64);
+    if args.balloon {
+        command.arg("-device").arg("virtio-balloon-pci");
+    }
 
-    // Set window name
-    command.arg("-name").arg("Redox OS x86_64");
+    // Kept running until QEMU exits, and not started by a dry run
+    let swtpm = if args.tpm && args.dry_run {
+        let socket_path = tpm::socket_path(&cache_dir, args.profile.as_ref());
+        tpm::args(&mut command, arch, &socket_path)?;
+        None
+    } else if args.tpm {
+        Some(tpm::Swtpm::start(
+            &mut command,
+            arch,
+            &cache_dir,
+            args.profile.as_ref(),
+        )?)
+    } else {
+        None
+    };
 
-    //TODO: kvm not always available
-    let kvm = true;
-    if kvm {
-        command.arg("-enable-kvm").arg("-cpu").arg("host");
+    // Kept running until QEMU exits, and not started by a dry run
+    let virtiofsd = if args.virtiofs && args.dry_run {
+        share::args_virtiofs(
+            &mut command,
+            &args.shares,
+            memory,
+            &cache_dir,
+            args.profile.as_ref(),
+        )?;
+        None
+    } else if args.virtiofs {
+        Some(share::Virtiofsd::start(
+            &mut command,
+            &args.shares,
+            memory,
+            &cache_dir,
+            args.profile.as_ref(),
+        )?)
     } else {
-        command.arg("-cpu").arg("max");
+        share::args_9p(&mut command, &args.shares)?;
+        None
+    };
+
+    // Entropy source, so the guest does not have to gather its own
+    if !args.no_rng {
+        let backend = if cfg!(unix) {
+            "rng-random,filename=/dev/urandom,id=rng0"
+        } else {
+            "rng-builtin,id=rng0"
+        };
+        command.arg("-object").arg(backend);
+        command.arg("-device").arg("virtio-rng-pci,rng=rng0");
     }
 
-    // Use q35 machine
-    command.arg("-machine").arg("q35");
+    for device in &args.usb_devices {
+        device.check_access()?;
+    }
+    // The default USB devices already include a tablet
+    let tablet = args.tablet && !arch.has_usb();
+    usb::args(&mut command, &args.usb_devices, tablet, arch.has_usb());
 
-    // Redox needs 2 GiB of RAM
-    command.arg("-m").arg("2048");
+    // Harddrive
+    if let Some(hd_path) = &hd_path {
+        resize::restore(hd_path)?;
+        let mut hd_format = image::format(hd_path)?;
+        if args.qcow2 && hd_format == "raw" {
+            if hd_path.starts_with(&cache_dir) {
+                image::convert_qcow2(hd_path)?;
+                hd_format = "qcow2";
+            } else {
+                log::warn!("not converting {hd_path:?} to qcow2, it is outside of the cache");
+            }
+        }
+        if args.ephemeral {
+            output::status!("changes to the harddrive will be discarded");
+        }
+        if let Some(name) = &args.load_state {
+            if hd_format != "qcow2" {
+                return Err("--load-state requires a qcow2 harddrive".into());
+            }
+            command.arg("-loadvm").arg(name);
+        }
+        disk::drive(
+            &mut command,
+            machine,
+            disk_if,
+            &hd_path.display().to_string(),
+            hd_format,
+            args.ephemeral,
+        );
+    }
 
-    // Use 4 CPUs
-    //TODO: detect host CPUs?
-    command.arg("-smp").arg("4");
+    // Livedisk
+    if let Some(livedisk_path) = &livedisk_path {
+        command.arg("-cdrom").arg(livedisk_path);
+        if arch.firmware().is_none() && !args.uefi && !args.secure_boot {
+            // Boot from CD-ROM, as the harddrive may be blank
+            command.arg("-boot").arg("d");
+        }
+    }
 
-    // Serial output
-    command.arg("-serial").arg("stdio");
+    // Process ID, used with the state file to find the running VM
+    let pid_path = instance::pid_path(&cache_dir, args.profile.as_ref());
+    if let Some(parent) = pid_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    command.arg("-pidfile").arg(&pid_path);
 
-    // HDA audio device
-    command.arg("-device").arg("ich9-intel-hda");
-    command.arg("-device").arg("hda-output");
+    // Management socket, used by subcommands to control the running VM
+    if cfg!(unix) {
+        let qmp_path = qmp::socket_path(&cache_dir, args.profile.as_ref());
+        qmp::args(&mut command, &qmp_path);
+    }
 
-    // E1000 ethernet device
-    command.arg("-netdev").arg("user,id=net0");
-    command.arg("-device").arg("e1000,netdev=net0");
+    if let Some(pcap_path) = &args.pcap {
+        pcap::args(&mut command, pcap_path);
+    }
 
-    // Downloaded QEMU BIOS
-    command.arg("-L").arg(qemu_dir.join("qemu-9.0.1/pc-bios"));
+    if args.agent {
+        let agent_path = agent::socket_path(&cache_dir, args.profile.as_ref());
+        agent::args(&mut command, &agent_path);
+    }
 
-    // Downloaded harddrive
-    command
-        .arg("-drive")
-        .arg(format!("file={},format=raw", hd_path.display()));
+    if let Some(port) = args.gdb {
+        output::status!("waiting for a debugger on port {port}, connect with rebox gdb");
+        gdb::args(&mut command, port);
+    }
 
     // Add any additional arguments from the command line
-    command.args(env::args().skip(1));
+    command.args(&args.qemu_args);
 
-    println!("running {:?}", command);
-    command.spawn()?.wait()?;
+    if args.dry_run {
+        let mut files = vec![("cache directory", cache_dir.clone())];
+        files.extend(hd_path.map(|path| ("harddrive", path)));
+        files.extend(livedisk_path.map(|path| ("livedisk", path)));
+        files.extend(serial_log.map(|path| ("serial log", path)));
+        files.push(("pidfile", pid_path));
+        if cfg!(unix) {
+            files.push((
+                "QMP socket",
+                qmp::socket_path(&cache_dir, args.profile.as_ref()),
+            ));
+        }
+        print_dry_run(&command, &files);
+        return Ok(());
+    }
+    if cfg!(unix) {
+        qmp::prepare_socket(&qmp::socket_path(&cache_dir, args.profile.as_ref()))?;
+    }
+    if let Some(pcap_path) = &args.pcap {
+        pcap::rotate(pcap_path)?;
+    }
+    if let Some(hd_path) = &hd_path {
+        image::repair_dirty(hd_path)?;
+    }
+
+    output::status!("running {:?}", command);
+    let _terminal = if args.console {
+        output::status!("press Ctrl-a x to quit, or Ctrl-a h for help");
+        Some(console::Terminal::save())
+    } else {
+        None
+    };
+    if args.detach {
+        let monitor_path = console::monitor_path(&cache_dir, args.profile.as_ref());
+        console::monitor_args(&mut command, &monitor_path)?;
+        // Output of QEMU goes to a log, and it is not stopped with rebox by the terminal
+        let log_path = Profile::runtime_path(&cache_dir, args.profile.as_ref(), "log");
+        let log = fs::File::create(&log_path)?;
+        command
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log);
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    }
+    let mut child = command.spawn()?;
+    let state_path = instance::state_path(&cache_dir, args.profile.as_ref());
+    let mut ports: Vec<String> = args.publish.iter().map(ToString::to_string).collect();
+    if let Some(vnc_display) = args.vnc {
+        ports.push(format!("{}:vnc", 5900 + u32::from(vnc_display)));
+    }
+    instance::Instance::new(args.profile.as_ref(), child.id(), ports, hd_path.clone())
+        .save(&state_path)?;
+    output::event(
+        "vm-started",
+        json!({
+            "pid": child.id(),
+            "profile": args.profile.as_ref().map(|profile| &profile.name),
+        }),
+    );
+    if args.detach {
+        let name = args
+            .profile
+            .as_ref()
+            .map_or("default", |profile| &profile.name);
+        output::status!("{name}");
+        if detached_serial {
+            let socket_path = serial::socket_path(&cache_dir, args.profile.as_ref());
+            output::status!("serial console on {}", socket_path.display());
+        }
+        // Helpers exit by themselves when QEMU disconnects from them
+        mem::forget(swtpm);
+        mem::forget(virtiofsd);
+        return Ok(());
+    }
+    if let Some(pcap_path) = &args.pcap {
+        // Rotation replaces the dump filter over QMP
+        if cfg!(unix) {
+            let qmp_path = qmp::socket_path(&cache_dir, args.profile.as_ref());
+            let max_size = args.pcap_max_size.unwrap_or(pcap::DEFAULT_MAX_SIZE);
+            pcap::watch(qmp_path, pcap_path.clone(), max_size);
+        }
+    }
+    let status = child.wait()?;
+    output::event("vm-exited", json!({ "code": status.code() }));
+    // QEMU removes the pidfile itself when it exits cleanly
+    let _ = fs::remove_file(&state_path);
+    Ok(())
+}
+
+/// Print the QEMU command with its environment and the files it uses, as a shell script that
+/// can be copied, or as a dry-run event when output is JSON
+fn print_dry_run(command: &process::Command, files: &[(&str, PathBuf)]) {
+    let env: Vec<(String, Option<String>)> = command
+        .get_envs()
+        .map(|(key, value)| {
+            (
+                key.to_string_lossy().into_owned(),
+                value.map(|value| value.to_string_lossy().into_owned()),
+            )
+        })
+        .collect();
+    if output::json() {
+        output::event(
+            "dry-run",
+            json!({
+                "program": command.get_program().to_string_lossy(),
+                "args": command
+                    .get_args()
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "env": env
+                    .into_iter()
+                    .map(|(key, value)| (key, json!(value)))
+                    .collect::<serde_json::Map<_, _>>(),
+                "files": files
+                    .iter()
+                    .map(|(name, path)| (name.to_string(), json!(path)))
+                    .collect::<serde_json::Map<_, _>>(),
+            }),
+        );
+        return;
+    }
+
+    for (name, path) in files {
+        println!("# {name}: {}", path.display());
+    }
+    for (key, value) in env {
+        match value {
+            Some(value) => println!("export {key}={}", util::shell_quote(&value)),
+            None => println!("unset {key}"),
+        }
+    }
+    // One option with its value per line
+    let mut line = util::shell_quote(&command.get_program().to_string_lossy());
+    for arg in command.get_args() {
+        let arg = util::shell_quote(&arg.to_string_lossy());
+        if arg.starts_with('-') {
+            println!("{line} \\");
+            line = format!("    {arg}");
+        } else {
+            line.push(' ');
+            line.push_str(&arg);
+        }
+    }
+    println!("{line}");
+}
+
+fn snapshot(args: &Args, command: &SnapshotCommand) -> Result<(), Box<dyn Error>> {
+    let cache_dir = cache_dir(args)?;
+    let hd_path =
+        image_source(args).harddrive_path(&cache_dir, &image_options(args, &cache_dir))?;
+    snapshot::run(command, &hd_path)
+}
+
+/// Save or restore the state of the running VM
+fn state(args: &Args, name: &str, restore: bool) -> Result<(), Box<dyn Error>> {
+    let cache_dir = cache_dir(args)?;
+    let hd_path =
+        image_source(args).harddrive_path(&cache_dir, &image_options(args, &cache_dir))?;
+    let qmp_path = qmp::socket_path(&cache_dir, args.profile.as_ref());
+    snapshot::state(&qmp_path, &hd_path, name, restore)
+}
+
+/// Print the CPU models supported by QEMU for the architecture
+fn cpu_models(args: &Args) -> Result<(), Box<dyn Error>> {
+    let arch = args.arch;
+    let machine = args.machine.unwrap_or(arch.default_machine());
+    let mut command = qemu::command(
+        arch,
+        machine,
+        args.qemu,
+        args.qemu_version.as_deref(),
+        &cache_dir(args)?,
+    )?;
+    let output = command.arg("-cpu").arg("help").output()?;
+    if !output.status.success() {
+        return Err(format!("QEMU failed to list CPU models with {}", output.status).into());
+    }
+
+    // Models are listed first, like "x86 Skylake-Client  Intel Core Processor (Skylake)",
+    // followed by a list of CPU flags
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in stdout.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("Available CPUs") {
+            continue;
+        }
+        if line.ends_with(':') {
+            break;
+        }
+        let line = line.strip_prefix("x86 ").unwrap_or(line);
+        let (model, description) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        println!("{model:<32} {}", description.trim());
+    }
+    Ok(())
+}
+
+/// Inflate or deflate the balloon of the running VM so the guest has size bytes of memory
+fn balloon(args: &Args, size: u64) -> Result<(), Box<dyn Error>> {
+    let qmp_path = qmp::socket_path(&cache_dir(args)?, args.profile.as_ref());
+    let mut client = qmp::Client::connect(&qmp_path)?;
+    client.balloon(size)?;
+    output::status!("set memory of the guest to {}", util::format_size(size));
+    Ok(())
+}
+
+/// Pause or resume the guest of the running VM
+fn pause(args: &Args, pause: bool) -> Result<(), Box<dyn Error>> {
+    let qmp_path = qmp::socket_path(&cache_dir(args)?, args.profile.as_ref());
+    let mut client = qmp::Client::connect(&qmp_path)?;
+    if pause {
+        client.stop()?;
+        output::status!("paused");
+    } else {
+        client.cont()?;
+        output::status!("resumed");
+    }
     Ok(())
 }
+
+fn resize(args: &Args, size: u64) -> Result<(), Box<dyn Error>> {
+    let cache_dir = cache_dir(args)?;
+    let hd_path =
+        image_source(args).harddrive_path(&cache_dir, &image_options(args, &cache_dir))?;
+    resize::run(&hd_path, size)
+}
+
+fn clean(args: &Args, options: &CleanOptions) -> Result<(), Box<dyn Error>> {
+    let cache_dir = cache_dir(args)?;
+    // Keep the selected harddrive, the record of the image it was created from, its size, and
+    // whether it needs to be checked
+    let hd_path =
+        image_source(args).harddrive_path(&cache_dir, &image_options(args, &cache_dir))?;
+    let keep = [
+        hd_path.clone(),
+        hd_path.with_extension("sha256"),
+        resize::size_path(&hd_path),
+        image::dirty_path(&hd_path),
+        cache::lock_path(&cache_dir),
+    ];
+    let _lock = cache::Lock::acquire(&cache_dir)?;
+    clean::run(&cache_dir, options, &keep)
+}
+
+/// Memory for the guest when none is selected, a quarter of the host memory from 2 to 8 GiB
+fn default_memory() -> u64 {
+    // Redox needs 2 GiB of RAM
+    const MIN: u64 = 2 << 30;
+    const MAX: u64 = 8 << 30;
+    match host::total_memory() {
+        Some(total) => (total / 4).clamp(MIN, MAX),
+        None => MIN,
+    }
+}
+
+/// CPUs for the guest when none are selected, the physical cores of the host up to 8
+fn default_cpus() -> usize {
+    host::physical_cores().clamp(1, 8)
+}
+
+fn cache_dir(args: &Args) -> Result<PathBuf, Box<dyn Error>> {
+    let cache_dir = match &args.cache_dir {
+        Some(cache_dir) => cache_dir.clone(),
+        None => dirs::cache_dir()
+            .ok_or("user cache directory not found")?
+            .join("rebox"),
+    };
+    fs::create_dir_all(&cache_dir)?;
+    Ok(cache_dir)
+}
+
+fn image_options(args: &Args, cache_dir: &Path) -> image::Options {
+    image::Options {
+        hd_dir: args
+            .profile
+            .as_ref()
+            .map(|profile| profile.cache_dir(cache_dir)),
+        update: args.update,
+        reset: args.reset,
+    }
+}
+
+fn net_options(args: &Args, machine: Machine) -> net::Options {
+    // Without --nic, there is one network card configured by --net, --nic-model, and --mac
+    let nics = if args.nics.is_empty() {
+        vec![Nic {
+            network: args.network.clone(),
+            model: args.nic_model,
+            mac: args.mac,
+        }]
+    } else {
+        args.nics.clone()
+    };
+    net::Options {
+        nics,
+        model: NicModel::default_for(machine),
+        mac: MacAddr::for_profile(args.profile.as_ref()),
+        publish: args.publish.clone(),
+        smb: args.smb.clone(),
+        restrict: args.net_restrict,
+        user_options: args.net_opts.clone().unwrap_or_default(),
+    }
+}
+
+/// Source of the harddrive image selected by the arguments
+fn image_source(args: &Args) -> Source {
+    if let Some(image) = &args.image {
+        Source::Local(image.clone())
+    } else if let Some(image_url) = &args.image_url {
+        Source::Url(image_url.clone(), args.image_sha256.clone())
+    } else {
+        let remote = Remote {
+            arch: args.arch,
+            channel: args.channel,
+            release: args.release.clone(),
+            signing_key: args.signing_key.clone(),
+        };
+        Source::Remote(remote, args.variant)
+    }
+}
@@ -0,0 +1,525 @@
+use std::{
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{arch::Machine, profile::Profile, util};
+
+/// Emulated network card
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NicModel {
+    VirtioNetPci,
+    E1000,
+    Rtl8139,
+}
+
+impl NicModel {
+    pub const ALL: [NicModel; 3] = [NicModel::VirtioNetPci, NicModel::E1000, NicModel::Rtl8139];
+
+    /// Name of the QEMU device
+    pub fn name(&self) -> &'static str {
+        match self {
+            NicModel::VirtioNetPci => "virtio-net-pci",
+            NicModel::E1000 => "e1000",
+            NicModel::Rtl8139 => "rtl8139",
+        }
+    }
+
+    /// Network card used when none is selected
+    pub fn default_for(machine: Machine) -> NicModel {
+        match machine {
+            // Only paravirtualized devices are used with microvm
+            Machine::Microvm => NicModel::VirtioNetPci,
+            _ => NicModel::E1000,
+        }
+    }
+}
+
+impl fmt::Display for NicModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for NicModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "virtio-net-pci" | "virtio-net" | "virtio" => Ok(NicModel::VirtioNetPci),
+            "e1000" => Ok(NicModel::E1000),
+            "rtl8139" => Ok(NicModel::Rtl8139),
+            _ => Err(format!(
+                "unknown NIC model {s:?}, expected one of {}",
+                NicModel::ALL.map(|model| model.name()).join(", ")
+            )),
+        }
+    }
+}
+
+/// Port forwarded from the host to the guest
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Publish {
+    pub host_port: u16,
+    pub guest_port: u16,
+    pub udp: bool,
+}
+
+impl fmt::Display for Publish {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.host_port, self.guest_port)?;
+        if self.udp {
+            f.write_str("/udp")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Publish {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port forward {s:?}, expected HOST:GUEST[/udp]");
+        let (ports, udp) = match s.split_once('/') {
+            Some((ports, "udp")) => (ports, true),
+            Some((ports, "tcp")) => (ports, false),
+            Some(_) => return Err(invalid()),
+            None => (s, false),
+        };
+        let (host_port, guest_port) = ports.split_once(':').ok_or_else(invalid)?;
+        Ok(Publish {
+            host_port: host_port.parse().map_err(|_| invalid())?,
+            guest_port: guest_port.parse().map_err(|_| invalid())?,
+            udp,
+        })
+    }
+}
+
+/// MAC address of the network card
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Address derived from the profile name, so it stays the same across runs
+    pub fn for_profile(profile: Option<&Profile>) -> MacAddr {
+        let name = profile.map_or("default", |profile| profile.name.as_str());
+        let hash = Sha256::digest(name.as_bytes());
+        // Locally administered range used by QEMU
+        MacAddr([0x52, 0x54, 0x00, hash[0], hash[1], hash[2]])
+    }
+
+    /// Address of the network card at index, counting up from this address
+    pub fn nth(&self, index: usize) -> MacAddr {
+        let mut mac = self.0;
+        mac[5] = mac[5].wrapping_add(index as u8);
+        MacAddr(mac)
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid MAC address {s:?}, expected like 52:54:00:12:34:56");
+        let mut mac = [0; 6];
+        let mut parts = s.split(':');
+        for byte in &mut mac {
+            let part = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        if mac[0] & 1 != 0 {
+            return Err(format!("invalid MAC address {s:?}, must not be multicast"));
+        }
+        Ok(MacAddr(mac))
+    }
+}
+
+/// How the network card is connected to the host
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Network {
+    /// User mode networking, which needs no privileges but only allows outbound connections
+    #[default]
+    User,
+    /// Existing tap device
+    Tap(String),
+    /// Tap device created by qemu-bridge-helper and added to a bridge
+    Bridge(String),
+    /// Ethernet frames sent over a socket, to connect VMs together, with QEMU socket options
+    /// like listen=:1234, connect=127.0.0.1:1234, or mcast=230.0.0.1:1234
+    Socket(String),
+}
+
+const BRIDGE_HELPERS: &[&str] = &[
+    "/usr/lib/qemu/qemu-bridge-helper",
+    "/usr/libexec/qemu-bridge-helper",
+    "/usr/local/libexec/qemu-bridge-helper",
+];
+
+impl Network {
+    /// Check that QEMU will be able to use the tap device, returning the bridge helper to use
+    /// with a bridge
+    fn check(&self) -> Result<Option<PathBuf>, String> {
+        match self {
+            Network::User | Network::Socket(_) => Ok(None),
+            Network::Tap(ifname) => {
+                if !Path::new("/sys/class/net").join(ifname).exists() {
+                    let user = env::var("USER").unwrap_or_else(|_| "$USER".to_string());
+                    return Err(format!(
+                        "tap device {ifname} does not exist, create it with \
+                         `sudo ip tuntap add dev {ifname} mode tap user {user}` and \
+                         `sudo ip link set {ifname} up`"
+                    ));
+                }
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/net/tun")
+                    .map_err(|err| format!("failed to open /dev/net/tun: {err}"))?;
+                Ok(None)
+            }
+            Network::Bridge(bridge) => {
+                let helper = BRIDGE_HELPERS
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|path| path.is_file())
+                    .ok_or("qemu-bridge-helper not found, please install QEMU")?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    // Creating the tap device requires root, unless given CAP_NET_ADMIN instead
+                    let metadata = fs::metadata(&helper).map_err(|err| err.to_string())?;
+                    if metadata.uid() != 0 || metadata.mode() & 0o4000 == 0 {
+                        log::warn!(
+                            "{} is not setuid root, so it may fail to create the tap device",
+                            helper.display()
+                        );
+                    }
+                }
+                // The helper only adds tap devices to bridges that are allowed
+                let conf = fs::read_to_string("/etc/qemu/bridge.conf").unwrap_or_default();
+                let allowed = conf.lines().any(|line| {
+                    let mut words = line.split_whitespace();
+                    words.next() == Some("allow")
+                        && words
+                            .next()
+                            .is_some_and(|name| name == bridge || name == "all")
+                });
+                if !allowed {
+                    log::warn!(
+                        "bridge {bridge} may not be allowed, add `allow {bridge}` to \
+                         /etc/qemu/bridge.conf"
+                    );
+                }
+                Ok(Some(helper))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::User => f.write_str("user"),
+            Network::Tap(ifname) => write!(f, "tap,ifname={ifname}"),
+            Network::Bridge(bridge) => write!(f, "tap,bridge={bridge}"),
+            Network::Socket(option) => write!(f, "socket,{option}"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        match options.next() {
+            Some("user") if s == "user" => Ok(Network::User),
+            Some("tap") => {
+                let mut network = Network::Tap("tap0".to_string());
+                for option in options {
+                    network = match option.split_once('=') {
+                        Some(("ifname", ifname)) if !ifname.is_empty() => {
+                            Network::Tap(ifname.to_string())
+                        }
+                        Some(("bridge", bridge)) if !bridge.is_empty() => {
+                            Network::Bridge(bridge.to_string())
+                        }
+                        _ => {
+                            return Err(format!(
+                                "invalid tap option {option:?}, expected ifname=<NAME> or \
+                                 bridge=<BRIDGE>"
+                            ))
+                        }
+                    };
+                }
+                Ok(network)
+            }
+            Some("socket") => match (options.next(), options.next()) {
+                (Some(option), None)
+                    if ["listen=", "connect=", "mcast="]
+                        .iter()
+                        .any(|prefix| option.starts_with(prefix)) =>
+                {
+                    Ok(Network::Socket(option.to_string()))
+                }
+                _ => Err(format!(
+                    "invalid socket network {s:?}, expected socket,listen=<ADDR>, \
+                     socket,connect=<ADDR>, or socket,mcast=<ADDR>"
+                )),
+            },
+            _ => Err(format!(
+                "unknown network {s:?}, expected user, tap[,ifname=<NAME>][,bridge=<BRIDGE>], \
+                 or socket,<listen|connect|mcast>=<ADDR>"
+            )),
+        }
+    }
+}
+
+/// Addressing options of user mode networking, like net=10.5.0.0/24,host=10.5.0.2
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UserOptions(Vec<(String, String)>);
+
+impl UserOptions {
+    /// Options that do not change what the guest can reach, unlike restrict or hostfwd
+    const KEYS: [&'static str; 8] = [
+        "net",
+        "host",
+        "dhcpstart",
+        "dns",
+        "dnssearch",
+        "domainname",
+        "hostname",
+        "tftp",
+    ];
+}
+
+impl fmt::Display for UserOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in &self.0 {
+            write!(f, ",{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for UserOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = Vec::new();
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some((key, value)) if UserOptions::KEYS.contains(&key) && !value.is_empty() => {
+                    options.push((key.to_string(), value.to_string()));
+                }
+                _ => {
+                    return Err(format!(
+                        "invalid user network option {option:?}, expected <KEY>=<VALUE> with a \
+                         key of {}",
+                        UserOptions::KEYS.join(", ")
+                    ))
+                }
+            }
+        }
+        Ok(UserOptions(options))
+    }
+}
+
+/// Network card with its own network, parsed from the network followed by optional
+/// model=<MODEL> and mac=<ADDRESS>
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Nic {
+    pub network: Network,
+    pub model: Option<NicModel>,
+    pub mac: Option<MacAddr>,
+}
+
+impl FromStr for Nic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut model = None;
+        let mut mac = None;
+        let mut network = Vec::new();
+        for option in s.split(',') {
+            match option.split_once('=') {
+                Some(("model", value)) => model = Some(value.parse()?),
+                Some(("mac", value)) => mac = Some(value.parse()?),
+                _ => network.push(option),
+            }
+        }
+        Ok(Nic {
+            network: network.join(",").parse()?,
+            model,
+            mac,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub nics: Vec<Nic>,
+    /// Model of network cards without one
+    pub model: NicModel,
+    /// Address of the first network card without one, counted up for the others
+    pub mac: MacAddr,
+    /// Ports forwarded from the host with user mode networking
+    pub publish: Vec<Publish>,
+    /// Directory shared over SMB with user mode networking
+    pub smb: Option<PathBuf>,
+    /// Isolate the guest from the host network with user mode networking
+    pub restrict: bool,
+    /// Addressing of user mode networking
+    pub user_options: UserOptions,
+}
+
+/// Samba server run by QEMU, unless QEMU was built with another path
+const SMBD: &str = "/usr/sbin/smbd";
+
+/// Check that QEMU is able to share a directory over SMB
+fn check_smb(dir: &Path) -> Result<(), String> {
+    if cfg!(windows) {
+        return Err("SMB sharing is not supported by QEMU on Windows".to_string());
+    }
+    if !dir.is_dir() {
+        return Err(format!("SMB directory {} does not exist", dir.display()));
+    }
+    // QEMU runs a private instance of Samba for the share
+    if !Path::new(SMBD).is_file() && util::which("smbd").is_none() {
+        return Err("smbd not found, install Samba to use --smb".to_string());
+    }
+    Ok(())
+}
+
+/// Add the network cards connected to their networks. The user mode networking options apply
+/// to the first card using user mode networking
+pub fn args(command: &mut Command, options: &Options) -> Result<(), String> {
+    let mut user_options = true;
+    for (i, nic) in options.nics.iter().enumerate() {
+        let helper = nic.network.check()?;
+        let netdev = match &nic.network {
+            Network::User => {
+                let mut netdev = format!("user,id=net{i}");
+                if user_options {
+                    user_options = false;
+                    netdev.push_str(&options.user_options.to_string());
+                    if options.restrict {
+                        // The guest can still reach the host services and forwarded ports
+                        netdev.push_str(",restrict=on");
+                    }
+                    for publish in &options.publish {
+                        // Only reachable from the host, not from the network the host is on
+                        let protocol = if publish.udp { "udp" } else { "tcp" };
+                        netdev.push_str(&format!(
+                            ",hostfwd={protocol}:127.0.0.1:{}-:{}",
+                            publish.host_port, publish.guest_port
+                        ));
+                    }
+                    if let Some(smb) = &options.smb {
+                        check_smb(smb)?;
+                        // Available to the guest at \\10.0.2.4\qemu
+                        netdev.push_str(&format!(",smb={}", smb.display()));
+                    }
+                }
+                netdev
+            }
+            // The device is already configured, so no scripts are run
+            Network::Tap(ifname) => {
+                format!("tap,id=net{i},ifname={ifname},script=no,downscript=no")
+            }
+            Network::Bridge(bridge) => {
+                let mut netdev = format!("bridge,id=net{i},br={bridge}");
+                if let Some(helper) = helper {
+                    netdev.push_str(&format!(",helper={}", helper.display()));
+                }
+                netdev
+            }
+            Network::Socket(option) => format!("socket,id=net{i},{option}"),
+        };
+        command.arg("-netdev").arg(netdev);
+        command.arg("-device").arg(format!(
+            "{},netdev=net{i},mac={}",
+            nic.model.unwrap_or(options.model).name(),
+            nic.mac.unwrap_or(options.mac.nth(i))
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish() {
+        let publish: Publish = "8080:80".parse().unwrap();
+        assert_eq!(
+            (publish.host_port, publish.guest_port, publish.udp),
+            (8080, 80, false)
+        );
+        let publish: Publish = "5353:53/udp".parse().unwrap();
+        assert_eq!(
+            (publish.host_port, publish.guest_port, publish.udp),
+            (5353, 53, true)
+        );
+        assert_eq!(publish.to_string(), "5353:53/udp");
+        let publish: Publish = "2222:22/tcp".parse().unwrap();
+        assert_eq!(publish.to_string(), "2222:22");
+    }
+
+    #[test]
+    fn publish_invalid() {
+        for s in [
+            "",
+            "8080",
+            "8080:",
+            ":80",
+            "8080:80/sctp",
+            "70000:80",
+            "a:80",
+        ] {
+            assert!(s.parse::<Publish>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn mac_addr() {
+        let mac: MacAddr = "52:54:00:AB:cd:0f".parse().unwrap();
+        assert_eq!(mac, MacAddr([0x52, 0x54, 0x00, 0xab, 0xcd, 0x0f]));
+        assert_eq!(mac.to_string(), "52:54:00:ab:cd:0f");
+        assert_eq!(mac.nth(1).to_string(), "52:54:00:ab:cd:10");
+    }
+
+    #[test]
+    fn mac_addr_invalid() {
+        for s in [
+            "",
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:78",
+            "52:54:00:12:34:5",
+            "52:54:00:12:34:zz",
+            "525400123456",
+            // Multicast
+            "01:00:5e:00:00:01",
+        ] {
+            assert!(s.parse::<MacAddr>().is_err(), "{s:?}");
+        }
+    }
+}
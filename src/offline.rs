@@ -0,0 +1,52 @@
+use serde_json::json;
+use std::{fmt, path::PathBuf};
+
+use crate::output::{self, Mode};
+
+/// Artifact that is not cached, so it would have to be downloaded
+pub struct Missing {
+    /// Where the artifact is cached
+    pub path: PathBuf,
+    /// What the artifact is, like "QEMU 9.0.1 source"
+    pub what: String,
+    /// Where the artifact is downloaded from
+    pub url: String,
+}
+
+impl fmt::Display for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}\n    from {}",
+            self.what,
+            self.path.display(),
+            self.url
+        )
+    }
+}
+
+/// Fail after listing the missing artifacts, if there are any
+pub fn check(missing: &[Missing]) -> Result<(), String> {
+    if missing.is_empty() {
+        return Ok(());
+    }
+    match output::mode() {
+        Mode::Human => {
+            eprintln!("not cached:");
+            for missing in missing {
+                eprintln!("  {missing}");
+            }
+        }
+        Mode::Json => {
+            for missing in missing {
+                output::event(
+                    "not-cached",
+                    json!({ "what": missing.what, "path": missing.path, "url": missing.url }),
+                );
+            }
+        }
+        // Only the error is printed
+        Mode::Quiet => {}
+    }
+    Err("--offline forbids downloading what is not cached".to_string())
+}
@@ -0,0 +1,63 @@
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+
+/// How rebox reports what it is doing
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Mode {
+    /// Messages and progress bars for a person
+    #[default]
+    Human,
+    /// Line-delimited JSON events for another program
+    Json,
+    /// Only warnings and errors
+    Quiet,
+}
+
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Use mode for all output, which must be done before anything is printed
+pub fn configure(mode: Mode) {
+    if MODE.set(mode).is_err() {
+        log::warn!("output mode was already configured");
+    }
+}
+
+/// How output is printed, which is for a person unless configured otherwise
+pub fn mode() -> Mode {
+    *MODE.get_or_init(Mode::default)
+}
+
+/// Whether output is line-delimited JSON events
+pub fn json() -> bool {
+    mode() == Mode::Json
+}
+
+/// Print an event with fields on its own line, only when output is JSON
+pub fn event(name: &str, fields: Value) {
+    if !json() {
+        return;
+    }
+    let mut event = json!({ "event": name });
+    if let (Some(event), Value::Object(fields)) = (event.as_object_mut(), fields) {
+        event.extend(fields);
+    }
+    println!("{event}");
+}
+
+/// Print an informational message, which is a message event when output is JSON and nothing
+/// when quiet
+pub fn message(message: &str) {
+    match mode() {
+        Mode::Human => println!("{message}"),
+        Mode::Json => event("message", json!({ "message": message })),
+        Mode::Quiet => {}
+    }
+}
+
+/// Print an informational message with format arguments, like println!
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::output::message(&format!($($arg)*))
+    };
+}
+pub(crate) use status;
@@ -0,0 +1,76 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration,
+};
+
+use serde_json::json;
+
+use crate::qmp;
+
+/// Size a capture may grow to before it is rotated, unless given
+pub const DEFAULT_MAX_SIZE: u64 = 100 << 20;
+
+/// Number of rotated captures kept next to the current one
+const KEEP: usize = 3;
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+/// Move the capture to FILE.1, shifting older captures up and removing the oldest
+pub fn rotate(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for index in (1..KEEP).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1))
+}
+
+/// Capture the traffic of the first network card to a pcap file, which overwrites it unless
+/// the previous capture is rotated first
+pub fn args(command: &mut Command, path: &Path) {
+    command.arg("-object").arg(format!(
+        "filter-dump,id=pcap0,netdev=net0,file={}",
+        path.display()
+    ));
+}
+
+/// Replace the dump filter with one writing to a new file, as QEMU cannot reopen it
+fn restart(qmp_path: &Path, path: &Path) -> Result<(), String> {
+    let mut client = qmp::Client::connect(qmp_path)?;
+    client.object_del("pcap0")?;
+    rotate(path).map_err(|err| format!("failed to rotate {}: {err}", path.display()))?;
+    client.object_add(
+        "filter-dump",
+        "pcap0",
+        json!({ "netdev": "net0", "file": path.display().to_string() }),
+    )?;
+    Ok(())
+}
+
+/// Rotate the capture whenever it grows over max_size bytes, until rebox exits
+pub fn watch(qmp_path: PathBuf, path: PathBuf, max_size: u64) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let size = fs::metadata(&path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        if size > max_size {
+            log::info!("rotating {}", path.display());
+            if let Err(err) = restart(&qmp_path, &path) {
+                log::warn!("failed to rotate {}, stopping: {err}", path.display());
+                return;
+            }
+        }
+    });
+}
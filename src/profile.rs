@@ -0,0 +1,75 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use crate::cache;
+
+/// A named VM with its own harddrive and saved settings
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+}
+
+impl Profile {
+    pub fn new(name: &str) -> Result<Self, String> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if valid {
+            Ok(Profile {
+                name: name.to_string(),
+            })
+        } else {
+            Err(format!(
+                "invalid profile name {name:?}, only letters, digits, - and _ are allowed"
+            ))
+        }
+    }
+
+    /// Directory containing the harddrive of this profile
+    pub fn cache_dir(&self, cache_dir: &Path) -> PathBuf {
+        cache_dir.join("profiles").join(&self.name)
+    }
+
+    /// Path of a runtime file, like a socket, of the VM for a profile or the VM without one
+    pub fn runtime_path(cache_dir: &Path, profile: Option<&Profile>, extension: &str) -> PathBuf {
+        let name = profile.map_or("default", |profile| profile.name.as_str());
+        cache_dir.join("run").join(format!("{name}.{extension}"))
+    }
+
+    /// File containing the saved arguments of this profile, one per line
+    pub fn args_path(&self) -> io::Result<PathBuf> {
+        let config_dir = dirs::config_dir().ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, "user config directory not found")
+        })?;
+        Ok(config_dir
+            .join("rebox")
+            .join("profiles")
+            .join(format!("{}.args", self.name)))
+    }
+
+    /// Load the saved arguments, returning None if the profile has not been saved
+    pub fn load_args(&self) -> io::Result<Option<Vec<String>>> {
+        match fs::read_to_string(self.args_path()?) {
+            Ok(data) => Ok(Some(data.lines().map(str::to_string).collect())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save_args(&self, args: &[String]) -> io::Result<()> {
+        let path = self.args_path()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut data = String::new();
+        for arg in args {
+            data.push_str(arg);
+            data.push('\n');
+        }
+        cache::write(&path, data)
+    }
+}
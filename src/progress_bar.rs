@@ -1,18 +1,165 @@
-use pbr::ProgressBar;
-use std::io::{Read, Result, Write};
+use pbr::{ProgressBar, Units};
+use serde_json::json;
+use std::io::{self, IsTerminal, Read, Result, Stdout, Write};
+use std::time::{Duration, Instant};
 
-pub struct ProgressBarRead<'p, 'r, P: Write + 'p, R: Read + 'r> {
-    pb: &'p mut ProgressBar<P>,
+use crate::{
+    output::{self, Mode},
+    util,
+};
+
+/// Time between progress bar refreshes and between progress events
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Time between progress lines when stdout is not a terminal, which keeps logs short
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Progress of a stage like download or verify, shown as a progress bar, or reported as
+/// progress events and a stage-complete event when output is JSON, or not shown when quiet
+///
+/// When stdout is not a terminal, like in CI or when piped to tee, the progress bar is
+/// replaced by a line with the percentage every few seconds
+pub struct Progress {
+    stage: &'static str,
+    total: u64,
+    done: u64,
+    bytes: bool,
+    pb: Option<ProgressBar<Stdout>>,
+    reported: Option<Instant>,
+}
+
+impl Progress {
+    /// Progress of a stage that handles total bytes
+    pub fn new(stage: &'static str, total: u64) -> Self {
+        Self::with_units(stage, total, true)
+    }
+
+    /// Progress of a stage that does total steps
+    pub fn count(stage: &'static str, total: u64) -> Self {
+        Self::with_units(stage, total, false)
+    }
+
+    fn with_units(stage: &'static str, total: u64, bytes: bool) -> Self {
+        let pb = (output::mode() == Mode::Human && io::stdout().is_terminal()).then(|| {
+            let mut pb = ProgressBar::new(total);
+            pb.message(&format!("{stage}: "));
+            pb.set_max_refresh_rate(Some(REFRESH));
+            if bytes {
+                pb.set_units(Units::Bytes);
+            }
+            pb
+        });
+        Progress {
+            stage,
+            total,
+            done: 0,
+            bytes,
+            pb,
+            reported: None,
+        }
+    }
+
+    pub fn set_total(&mut self, total: u64) {
+        self.total = total;
+        if let Some(pb) = &mut self.pb {
+            pb.total = total;
+        }
+    }
+
+    pub fn set(&mut self, done: u64) {
+        self.done = done;
+        match &mut self.pb {
+            Some(pb) => {
+                pb.set(done);
+            }
+            None => self.report(),
+        }
+    }
+
+    pub fn add(&mut self, count: u64) {
+        self.set(self.done + count);
+    }
+
+    /// Report progress as an event, or as a line when stdout is not a terminal, at most once
+    /// per interval
+    fn report(&mut self) {
+        let interval = match output::mode() {
+            Mode::Human => LOG_INTERVAL,
+            Mode::Json => REFRESH,
+            Mode::Quiet => return,
+        };
+        if !self.due(interval) {
+            return;
+        }
+        if !output::json() {
+            output::status!("{}: {}", self.stage, self.summary());
+            return;
+        }
+        // Downloads have their own event, as they are the stage that takes the longest
+        let name = if self.stage == "download" {
+            "download-progress"
+        } else {
+            "progress"
+        };
+        output::event(
+            name,
+            json!({ "stage": self.stage, "done": self.done, "total": self.total }),
+        );
+    }
+
+    /// Check if progress is reported now, which is at most once per interval
+    fn due(&mut self, interval: Duration) -> bool {
+        if self
+            .reported
+            .is_some_and(|reported| reported.elapsed() < interval)
+        {
+            return false;
+        }
+        self.reported = Some(Instant::now());
+        true
+    }
+
+    /// Percentage done with the amounts, like 42% (1.2 GiB of 2.9 GiB)
+    fn summary(&self) -> String {
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        let amount = |count| {
+            if self.bytes {
+                util::format_size(count)
+            } else {
+                count.to_string()
+            }
+        };
+        format!(
+            "{percent}% ({} of {})",
+            amount(self.done),
+            amount(self.total)
+        )
+    }
+
+    pub fn finish(self) {
+        match self.pb {
+            Some(mut pb) => pb.finish_println(""),
+            None if output::json() => output::event(
+                "stage-complete",
+                json!({ "stage": self.stage, "done": self.done, "total": self.total }),
+            ),
+            None => output::status!("{}: {}, done", self.stage, self.summary()),
+        }
+    }
+}
+
+pub struct ProgressBarRead<'p, 'r, R: Read + 'r> {
+    pb: &'p mut Progress,
     r: &'r mut R,
 }
 
-impl<'p, 'r, P: Write, R: Read> ProgressBarRead<'p, 'r, P, R> {
-    pub fn new(pb: &'p mut ProgressBar<P>, r: &'r mut R) -> ProgressBarRead<'p, 'r, P, R> {
-        ProgressBarRead { pb: pb, r: r }
+impl<'p, 'r, R: Read> ProgressBarRead<'p, 'r, R> {
+    pub fn new(pb: &'p mut Progress, r: &'r mut R) -> ProgressBarRead<'p, 'r, R> {
+        ProgressBarRead { pb, r }
     }
 }
 
-impl<'p, 'r, P: Write, R: Read> Read for ProgressBarRead<'p, 'r, P, R> {
+impl<'p, 'r, R: Read> Read for ProgressBarRead<'p, 'r, R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let count = self.r.read(buf)?;
         self.pb.add(count as u64);
@@ -20,18 +167,18 @@ impl<'p, 'r, P: Write, R: Read> Read for ProgressBarRead<'p, 'r, P, R> {
     }
 }
 
-pub struct ProgressBarWrite<'p, 'w, P: Write + 'p, W: Write + 'w> {
-    pb: &'p mut ProgressBar<P>,
+pub struct ProgressBarWrite<'p, 'w, W: Write + 'w> {
+    pb: &'p mut Progress,
     w: &'w mut W,
 }
 
-impl<'p, 'w, P: Write, W: Write> ProgressBarWrite<'p, 'w, P, W> {
-    pub fn new(pb: &'p mut ProgressBar<P>, w: &'w mut W) -> ProgressBarWrite<'p, 'w, P, W> {
-        ProgressBarWrite { pb: pb, w: w }
+impl<'p, 'w, W: Write> ProgressBarWrite<'p, 'w, W> {
+    pub fn new(pb: &'p mut Progress, w: &'w mut W) -> ProgressBarWrite<'p, 'w, W> {
+        ProgressBarWrite { pb, w }
     }
 }
 
-impl<'p, 'w, P: Write, W: Write> Write for ProgressBarWrite<'p, 'w, P, W> {
+impl<'p, 'w, W: Write> Write for ProgressBarWrite<'p, 'w, W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         let count = self.w.write(buf)?;
         self.pb.add(count as u64);
@@ -42,3 +189,48 @@ impl<'p, 'w, P: Write, W: Write> Write for ProgressBarWrite<'p, 'w, P, W> {
         self.w.flush()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_bytes() {
+        let mut progress = Progress::new("download", 4 << 20);
+        progress.set(1 << 20);
+        assert_eq!(progress.summary(), "25% (1.0 MiB of 4.0 MiB)");
+    }
+
+    #[test]
+    fn summary_of_steps() {
+        let mut progress = Progress::count("build", 3);
+        progress.add(2);
+        assert_eq!(progress.summary(), "66% (2 of 3)");
+    }
+
+    #[test]
+    fn summary_of_nothing_is_complete() {
+        assert_eq!(Progress::count("build", 0).summary(), "100% (0 of 0)");
+    }
+
+    #[test]
+    fn reported_once_per_interval() {
+        let mut progress = Progress::count("build", 10);
+        assert!(progress.due(LOG_INTERVAL));
+        assert!(!progress.due(LOG_INTERVAL));
+        assert!(progress.due(Duration::ZERO));
+    }
+
+    #[test]
+    fn counts_bytes_read_and_written() {
+        let mut read = Progress::new("verify", 3);
+        let mut written = Progress::new("extract", 3);
+        let mut data: &[u8] = b"abc";
+        io::copy(
+            &mut ProgressBarRead::new(&mut read, &mut data),
+            &mut ProgressBarWrite::new(&mut written, &mut io::sink()),
+        )
+        .unwrap();
+        assert_eq!((read.done, written.done), (3, 3));
+    }
+}
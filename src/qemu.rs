@@ -0,0 +1,531 @@
+#[cfg(feature = "aarch64")]
+use ::qemu::QEMU_AARCH64_SOFTMMU;
+use ::qemu::QEMU_X86_64_SOFTMMU;
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::{
+    arch::{Arch, Machine},
+    args::QemuChoice,
+    cache,
+    offline::Missing,
+    output,
+    progress_bar::Progress,
+    store, util,
+};
+
+/// Server that QEMU source releases are published on
+pub const SERVER: &str = "https://download.qemu.org";
+
+/// QEMU source release, which provides the BIOS files and is built when there is no binary
+pub struct Release {
+    pub version: &'static str,
+    /// Hash of the source tarball, which is verified before it is built
+    pub sha256: &'static str,
+}
+
+impl Release {
+    pub fn url(&self) -> String {
+        format!("{SERVER}/{}", self.tarball_name())
+    }
+
+    fn tarball_name(&self) -> String {
+        format!("qemu-{}.tar.xz", self.version)
+    }
+
+    /// Downloaded source tarball in the store, only needed until it is extracted
+    pub fn tarball(&self, cache_dir: &Path) -> Option<PathBuf> {
+        store::cached(cache_dir, &self.url(), Some(self.sha256))
+            .or_else(|| Some(self.legacy_tarball_path(cache_dir)).filter(|path| path.is_file()))
+    }
+
+    /// Where the source tarball was downloaded to before the store was used
+    pub fn legacy_tarball_path(&self, cache_dir: &Path) -> PathBuf {
+        cache_dir.join(self.tarball_name())
+    }
+
+    /// Extracted source, kept side by side with other versions
+    pub fn source_dir(&self, cache_dir: &Path) -> PathBuf {
+        cache_dir
+            .join("qemu")
+            .join(format!("qemu-{}", self.version))
+    }
+}
+
+/// QEMU releases that can be selected with --qemu-version
+pub const RELEASES: &[Release] = &[Release {
+    version: "9.0.1",
+    sha256: "d0f4db0fbd151c0cf16f84aeb2a500f6e95009732546f44dafab8d2049bbb805",
+}];
+
+/// Release that the embedded binaries are used with
+pub const EMBEDDED_VERSION: &str = "9.0.1";
+
+/// Find a release in the version table
+pub fn release(version: &str) -> Result<&'static Release, String> {
+    RELEASES
+        .iter()
+        .find(|release| release.version == version)
+        .ok_or_else(|| {
+            format!(
+                "unknown QEMU version {version:?}, expected one of {}",
+                RELEASES
+                    .iter()
+                    .map(|release| release.version)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// Oldest QEMU version known to run Redox with the default devices
+const SYSTEM_QEMU_MIN_VERSION: (u32, u32) = (6, 2);
+
+/// QEMU system emulators embedded in rebox, by file name
+pub fn embedded_binaries() -> Vec<(&'static str, &'static [u8])> {
+    #[allow(unused_mut)]
+    let mut binaries = vec![("qemu-system-x86_64", QEMU_X86_64_SOFTMMU)];
+    // Only embedded when built with the aarch64 feature, as it adds a large binary
+    #[cfg(feature = "aarch64")]
+    binaries.push(("qemu-system-aarch64", QEMU_AARCH64_SOFTMMU));
+    binaries
+}
+
+fn embedded_binary(arch: Arch) -> Option<&'static [u8]> {
+    embedded_binaries()
+        .into_iter()
+        .find(|(name, _)| *name == arch.qemu_system())
+        .map(|(_, binary)| binary)
+}
+
+/// Prepare the QEMU command used to run the architecture, with a specific QEMU version if
+/// one is given
+pub fn command(
+    arch: Arch,
+    machine: Machine,
+    choice: QemuChoice,
+    version: Option<&str>,
+    cache_dir: &Path,
+) -> Result<Command, Box<dyn Error>> {
+    let release = release(version.unwrap_or(EMBEDDED_VERSION))?;
+    let embedded = if release.version == EMBEDDED_VERSION {
+        embedded_binary(arch)
+    } else {
+        None
+    };
+    match choice {
+        // A specific version may only be provided by rebox
+        QemuChoice::Auto if version.is_some() => {
+            provided_qemu(arch, release, embedded, cache_dir)
+        }
+        // Without an embedded binary for the architecture, QEMU is built from source
+        QemuChoice::Auto => match system_qemu(arch, machine) {
+            Ok(qemu_system) => Ok(Command::new(qemu_system)),
+            Err(err) => {
+                if embedded.is_some() {
+                    log::info!("using embedded QEMU: {err}");
+                } else {
+                    log::info!("building QEMU from source: {err}");
+                }
+                provided_qemu(arch, release, embedded, cache_dir)
+            }
+        },
+        QemuChoice::Embedded if embedded.is_some() => {
+            provided_qemu(arch, release, embedded, cache_dir)
+        }
+        QemuChoice::Embedded => Err(format!(
+            "no embedded QEMU {} for {arch}, remove --embedded-qemu or build rebox with the {arch} feature",
+            release.version
+        )
+        .into()),
+        QemuChoice::System => Ok(Command::new(system_qemu(arch, machine)?)),
+    }
+}
+
+/// Download needed by command, if the QEMU source is not cached. With uefi, the UEFI
+/// firmware from the source is also needed
+pub fn missing(
+    arch: Arch,
+    machine: Machine,
+    choice: QemuChoice,
+    version: Option<&str>,
+    cache_dir: &Path,
+    uefi: bool,
+) -> Result<Option<Missing>, String> {
+    let release = release(version.unwrap_or(EMBEDDED_VERSION))?;
+    let embedded = release.version == EMBEDDED_VERSION && embedded_binary(arch).is_some();
+    // Follows the choice made by command
+    let provided = match choice {
+        QemuChoice::Auto if version.is_some() => true,
+        QemuChoice::Auto => find_system_qemu(arch, machine).is_err(),
+        QemuChoice::Embedded => embedded,
+        QemuChoice::System => false,
+    };
+    if !(provided || uefi)
+        || release.source_dir(cache_dir).is_dir()
+        || release.tarball(cache_dir).is_some()
+    {
+        return Ok(None);
+    }
+    Ok(Some(Missing {
+        path: store::path(cache_dir, release.sha256),
+        what: format!("QEMU {} source", release.version),
+        url: release.url(),
+    }))
+}
+
+/// Find an installed QEMU that is able to run the architecture, using its own firmware
+fn system_qemu(arch: Arch, machine: Machine) -> Result<PathBuf, Box<dyn Error>> {
+    let (qemu_system, version) = find_system_qemu(arch, machine)?;
+    output::status!("using {qemu_system:?} version {version}");
+    Ok(qemu_system)
+}
+
+/// Path and version of an installed QEMU that is able to run the architecture
+fn find_system_qemu(arch: Arch, machine: Machine) -> Result<(PathBuf, String), Box<dyn Error>> {
+    let qemu_system = util::which(arch.qemu_system())
+        .ok_or_else(|| format!("{} not found, please install QEMU", arch.qemu_system()))?;
+
+    // Output starts with "QEMU emulator version 8.2.2"
+    let output = Command::new(&qemu_system).arg("--version").output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout
+        .split_whitespace()
+        .nth(3)
+        .ok_or_else(|| format!("failed to get version of {qemu_system:?}"))?;
+    let mut parts = version
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    if (major, minor) < SYSTEM_QEMU_MIN_VERSION {
+        let (min_major, min_minor) = SYSTEM_QEMU_MIN_VERSION;
+        return Err(format!(
+            "{qemu_system:?} is version {version}, at least {min_major}.{min_minor} is required"
+        )
+        .into());
+    }
+
+    let output = Command::new(&qemu_system)
+        .arg("-machine")
+        .arg("help")
+        .output()?;
+    let machine = machine.name();
+    let supported = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.split_whitespace().next() == Some(machine));
+    if !supported {
+        return Err(format!("{qemu_system:?} does not support the {machine} machine").into());
+    }
+
+    let version = version.to_string();
+    Ok((qemu_system, version))
+}
+
+/// Download and extract the source of a QEMU release
+fn source(release: &Release, cache_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let sha256 = release.sha256;
+    let source_dir = release.source_dir(cache_dir);
+    let entry = store::Entry {
+        kind: "qemu".to_string(),
+        name: release.tarball_name(),
+        arch: None,
+        version: Some(release.version.to_string()),
+        url: release.url(),
+        sha256: Some(sha256.to_string()),
+    };
+    // Older versions of rebox only downloaded the embedded release, to qemu.tar.xz
+    let legacy = || -> io::Result<PathBuf> {
+        let unversioned = cache_dir.join("qemu.tar.xz");
+        if release.version == EMBEDDED_VERSION
+            && unversioned.is_file()
+            && util::sha256_progress(&unversioned)? == sha256
+        {
+            Ok(unversioned)
+        } else {
+            Ok(release.legacy_tarball_path(cache_dir))
+        }
+    };
+    // Hash of the tarball the source was extracted from, in SHA256SUM format
+    let source_path = source_dir.join("rebox.sha256");
+    let source = format!("{sha256}  qemu-{}.tar.xz\n", release.version);
+    if source_dir.is_dir() {
+        let current = match fs::read_to_string(&source_path) {
+            Ok(source) => source.split_whitespace().next() == Some(sha256),
+            // Extracted by older versions of rebox without recording the tarball, which was
+            // always the one of the embedded release, so it is kept once the tarball verifies
+            Err(err)
+                if err.kind() == ErrorKind::NotFound && release.version == EMBEDDED_VERSION =>
+            {
+                store::fetch(cache_dir, entry.clone(), &legacy()?)?;
+                cache::write(&source_path, &source)?;
+                true
+            }
+            Err(_) => false,
+        };
+        if current {
+            return Ok(source_dir);
+        }
+        output::status!("QEMU {} source is outdated", release.version);
+        fs::remove_dir_all(&source_dir)?;
+    }
+
+    output::status!("downloading QEMU {} source", release.version);
+    let (qemu_tar_xz, _) = store::fetch(cache_dir, entry, &legacy()?)?;
+
+    output::status!("extracting QEMU {} source", release.version);
+    let qemu_partial = cache_dir
+        .join("qemu")
+        .join(format!("qemu-{}.partial", release.version));
+    if qemu_partial.is_dir() {
+        // Left behind by an interrupted extraction, as the cache is locked while extracting
+        fs::remove_dir_all(&qemu_partial)?;
+    }
+    util::extract_progress(&qemu_tar_xz, &qemu_partial)?;
+    // The tarball contains a qemu-VERSION directory
+    let extracted = qemu_partial.join(source_dir.file_name().unwrap_or_default());
+    cache::write(&extracted.join("rebox.sha256"), source)?;
+    fs::rename(&extracted, &source_dir)?;
+    fs::remove_dir_all(&qemu_partial)?;
+    Ok(source_dir)
+}
+
+/// Prepare a QEMU binary provided by rebox, along with the downloaded QEMU BIOS. The embedded
+/// binary is used if there is one, otherwise QEMU is built from source
+fn provided_qemu(
+    arch: Arch,
+    release: &Release,
+    embedded: Option<&[u8]>,
+    cache_dir: &Path,
+) -> Result<Command, Box<dyn Error>> {
+    let source_dir = source(release, cache_dir)?;
+
+    let pc_bios = source_dir.join("pc-bios");
+    if let Some(firmware) = arch.firmware() {
+        firmware_from_source(&pc_bios, firmware)?;
+    }
+
+    let qemu_system = match embedded {
+        Some(binary) => {
+            let qemu_system = extract_binary(arch, binary, cache_dir)?;
+            // The embedded binary may need a newer glibc than the host has
+            match runs(&qemu_system) {
+                Ok(()) => qemu_system,
+                Err(err) => {
+                    log::warn!("embedded QEMU does not run, building it from source: {err}");
+                    build_from_source(arch, release, cache_dir, &source_dir)?
+                }
+            }
+        }
+        None => build_from_source(arch, release, cache_dir, &source_dir)?,
+    };
+
+    let mut command = Command::new(qemu_system);
+
+    // Downloaded QEMU BIOS
+    command.arg("-L").arg(pc_bios);
+
+    Ok(command)
+}
+
+/// Write an embedded QEMU binary to the cache, extracting it again if rebox was upgraded or
+/// the extracted file is corrupted
+fn extract_binary(arch: Arch, binary: &[u8], cache_dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let qemu_system = cache_dir.join(arch.qemu_system());
+    // Hash of the embedded binary, recorded in SHA256SUM format
+    let source_path = qemu_system.with_extension("sha256");
+    if unchanged(&qemu_system, &source_path, binary) {
+        return Ok(qemu_system);
+    }
+    let sha256 = util::sha256(&mut &binary[..])?;
+
+    let current = if qemu_system.is_file() {
+        let recorded = fs::read_to_string(&source_path).unwrap_or_default();
+        if recorded.split_whitespace().next() != Some(sha256.as_str()) {
+            output::status!("embedded QEMU binary has changed");
+            false
+        } else if util::sha256(&mut fs::File::open(&qemu_system)?)? != sha256 {
+            log::warn!("{qemu_system:?} is corrupted");
+            false
+        } else {
+            true
+        }
+    } else {
+        false
+    };
+
+    if !current {
+        output::status!("extracting QEMU binary");
+        let qemu_system_partial = cache::partial_path(&qemu_system);
+        if qemu_system_partial.exists() {
+            // Left read-only by an interrupted extraction
+            fs::remove_file(&qemu_system_partial)?;
+        }
+        fs::write(&qemu_system_partial, binary)?;
+
+        #[cfg(unix)]
+        {
+            output::status!("marking QEMU binary as read-only and executable");
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&qemu_system_partial, fs::Permissions::from_mode(0o555))?;
+        }
+
+        cache::commit(&qemu_system_partial, &qemu_system)?;
+    }
+    // Written after every check, so it is newer than rebox and the binary until one changes
+    cache::write(&source_path, format!("{sha256}  {}\n", arch.qemu_system()))?;
+    Ok(qemu_system)
+}
+
+/// Whether the extracted binary is unchanged since the hash of the embedded binary was
+/// recorded, in which case neither is hashed again until rebox is upgraded or the file changes
+fn unchanged(qemu_system: &Path, source_path: &Path, binary: &[u8]) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let (Ok(checked), Ok(extracted), Ok(rebox)) = (
+        modified(source_path),
+        fs::metadata(qemu_system),
+        env::current_exe().and_then(|exe| modified(&exe)),
+    ) else {
+        return false;
+    };
+    extracted.len() == binary.len() as u64
+        && extracted
+            .modified()
+            .is_ok_and(|modified| modified <= checked)
+        && rebox <= checked
+}
+
+/// Path of a firmware file from the source of a QEMU release, downloading the source and
+/// decompressing the file if needed
+pub fn firmware(
+    version: Option<&str>,
+    name: &str,
+    cache_dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let release = release(version.unwrap_or(EMBEDDED_VERSION))?;
+    let pc_bios = source(release, cache_dir)?.join("pc-bios");
+    firmware_from_source(&pc_bios, name)?;
+    Ok(pc_bios.join(name))
+}
+
+/// Decompress UEFI firmware, which the QEMU source only contains compressed with bzip2
+fn firmware_from_source(pc_bios: &Path, firmware: &str) -> Result<(), Box<dyn Error>> {
+    if pc_bios.join(firmware).is_file() {
+        return Ok(());
+    }
+
+    output::status!("decompressing {firmware}");
+    let bzip2 = util::which("bzip2").ok_or("bzip2 not found, please install it")?;
+    let path = pc_bios.join(firmware);
+    let partial = cache::partial_path(&path);
+    let status = Command::new(bzip2)
+        .arg("--decompress")
+        .arg("--stdout")
+        .arg(pc_bios.join(format!("{firmware}.bz2")))
+        .stdout(fs::File::create(&partial)?)
+        .status()?;
+    if !status.success() {
+        return Err(format!("bzip2 failed with {status}").into());
+    }
+    cache::commit(&partial, &path)?;
+    Ok(())
+}
+
+/// Check that a QEMU binary is able to run on this host
+fn runs(qemu_system: &Path) -> Result<(), Box<dyn Error>> {
+    let output = Command::new(qemu_system).arg("--version").output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} ({})",
+            String::from_utf8_lossy(&output.stderr).trim(),
+            output.status
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Configure and build the QEMU system emulator for an architecture from the QEMU source,
+/// caching the result
+fn build_from_source(
+    arch: Arch,
+    release: &Release,
+    cache_dir: &Path,
+    source_dir: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let qemu_build = cache_dir.join("qemu-build").join(release.version);
+    let qemu_system = qemu_build.join(arch.qemu_system());
+    if qemu_system.is_file() && runs(&qemu_system).is_ok() {
+        return Ok(qemu_system);
+    }
+
+    // qemu-system-x86_64 is built by the x86_64-softmmu target
+    let target = format!(
+        "{}-softmmu",
+        arch.qemu_system().trim_start_matches("qemu-system-")
+    );
+    let build_dir = qemu_build.join(format!("build-{target}"));
+    fs::create_dir_all(&build_dir)?;
+
+    output::status!("configuring QEMU {} {target}", release.version);
+    let status = Command::new(source_dir.join("configure"))
+        .current_dir(&build_dir)
+        .arg(format!("--target-list={target}"))
+        .arg("--disable-docs")
+        .arg("--disable-tools")
+        .arg("--disable-werror")
+        .status()?;
+    if !status.success() {
+        return Err(format!(
+            "QEMU configure failed with {status}, please install the QEMU build dependencies"
+        )
+        .into());
+    }
+
+    output::status!("building QEMU {} {target}", release.version);
+    let make = util::which("make").ok_or("make not found, please install it")?;
+    let jobs = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+    let mut child = Command::new(make)
+        .current_dir(&build_dir)
+        .arg(format!("-j{jobs}"))
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Ninja prints the progress of each step like [12/3456]
+    let mut pb: Option<Progress> = None;
+    if let Some(stdout) = child.stdout.take() {
+        for line in BufReader::new(stdout).lines() {
+            let line = line?;
+            let progress = line
+                .strip_prefix('[')
+                .and_then(|line| line.split_once(']'))
+                .and_then(|(progress, _)| progress.split_once('/'))
+                .and_then(|(done, total)| Some((done.parse().ok()?, total.parse().ok()?)));
+            let Some((done, total)) = progress else {
+                log::debug!("{line}");
+                continue;
+            };
+            let pb = pb.get_or_insert_with(|| Progress::count("build", total));
+            pb.set_total(total);
+            pb.set(done);
+        }
+    }
+    if let Some(pb) = pb {
+        pb.finish();
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("QEMU build failed with {status}").into());
+    }
+
+    let partial = cache::partial_path(&qemu_system);
+    fs::copy(build_dir.join(arch.qemu_system()), &partial)?;
+    cache::commit(&partial, &qemu_system)?;
+    Ok(qemu_system)
+}
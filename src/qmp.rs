@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[cfg(unix)]
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+};
+
+use serde_json::{json, Value};
+
+use crate::profile::Profile;
+
+/// Path of the QMP socket of the VM for a profile, or the VM without a profile
+pub fn socket_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "qmp")
+}
+
+/// Add a QMP socket at path
+pub fn args(command: &mut Command, path: &Path) {
+    command
+        .arg("-qmp")
+        .arg(format!("unix:{},server=on,wait=off", path.display()));
+}
+
+/// Prepare the directory of the QMP socket at path, removing the socket left behind by a
+/// previous run
+pub fn prepare_socket(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Connection to the QMP socket of a running VM
+#[cfg(unix)]
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+#[cfg(unix)]
+impl Client {
+    /// Connect to the socket and leave capabilities negotiation mode, so commands can be run
+    pub fn connect(path: &Path) -> Result<Self, String> {
+        let writer = UnixStream::connect(path).map_err(|err| {
+            format!(
+                "failed to connect to {}, is the VM running? {err}",
+                path.display()
+            )
+        })?;
+        let reader = BufReader::new(writer.try_clone().map_err(|err| err.to_string())?);
+        let mut client = Client { reader, writer };
+        // The server greets with its version before accepting commands
+        client.read()?;
+        client.execute("qmp_capabilities", None)?;
+        Ok(client)
+    }
+
+    /// Read the next message that is not an asynchronous event
+    fn read(&mut self) -> Result<Value, String> {
+        loop {
+            let mut line = String::new();
+            let count = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|err| format!("failed to read from QMP socket: {err}"))?;
+            if count == 0 {
+                return Err("QMP socket closed by QEMU".to_string());
+            }
+            let message: Value = serde_json::from_str(&line)
+                .map_err(|err| format!("invalid QMP message {line:?}: {err}"))?;
+            if message.get("event").is_none() {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Run a command with optional arguments, returning its result
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value, String> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        writeln!(self.writer, "{request}")
+            .map_err(|err| format!("failed to write to QMP socket: {err}"))?;
+
+        let mut response = self.read()?;
+        if let Some(error) = response.get("error") {
+            let desc = error["desc"].as_str().unwrap_or("unknown error");
+            return Err(format!("QMP command {command} failed: {desc}"));
+        }
+        Ok(response["return"].take())
+    }
+
+    /// Run a human monitor command, for features without a QMP command, returning its output
+    pub fn human_command(&mut self, command_line: &str) -> Result<String, String> {
+        let output = self.execute(
+            "human-monitor-command",
+            Some(json!({ "command-line": command_line })),
+        )?;
+        Ok(output.as_str().unwrap_or_default().to_string())
+    }
+}
+
+/// Commands with typed arguments and results
+impl Client {
+    /// Run state of the VM, like running, paused, or shutdown
+    pub fn query_status(&mut self) -> Result<String, String> {
+        let status = self.execute("query-status", None)?;
+        status["status"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("invalid QMP status {status}"))
+    }
+
+    /// Ask the guest to power off, like pressing the power button
+    pub fn system_powerdown(&mut self) -> Result<(), String> {
+        self.execute("system_powerdown", None)?;
+        Ok(())
+    }
+
+    /// Pause the guest CPUs
+    pub fn stop(&mut self) -> Result<(), String> {
+        self.execute("stop", None)?;
+        Ok(())
+    }
+
+    /// Resume the guest CPUs after stop
+    pub fn cont(&mut self) -> Result<(), String> {
+        self.execute("cont", None)?;
+        Ok(())
+    }
+
+    /// Exit QEMU immediately
+    pub fn quit(&mut self) -> Result<(), String> {
+        self.execute("quit", None)?;
+        Ok(())
+    }
+
+    /// Set the memory of the guest to size bytes with the balloon device
+    pub fn balloon(&mut self, size: u64) -> Result<(), String> {
+        self.execute("balloon", Some(json!({ "value": size })))?;
+        Ok(())
+    }
+
+    /// Write the display to path, in the PPM format
+    pub fn screendump(&mut self, path: &Path) -> Result<(), String> {
+        self.execute("screendump", Some(json!({ "filename": path })))?;
+        Ok(())
+    }
+
+    /// Send input events, like key presses, to the guest
+    pub fn input_send_event(&mut self, events: Vec<Value>) -> Result<(), String> {
+        self.execute("input-send-event", Some(json!({ "events": events })))?;
+        Ok(())
+    }
+
+    /// Add an object, like a filter, with its type and properties
+    pub fn object_add(&mut self, qom_type: &str, id: &str, props: Value) -> Result<(), String> {
+        let mut arguments = json!({ "qom-type": qom_type, "id": id });
+        if let (Some(arguments), Value::Object(props)) = (arguments.as_object_mut(), props) {
+            arguments.extend(props);
+        }
+        self.execute("object-add", Some(arguments))?;
+        Ok(())
+    }
+
+    /// Remove an object added with object_add or on the command line
+    pub fn object_del(&mut self, id: &str) -> Result<(), String> {
+        self.execute("object-del", Some(json!({ "id": id })))?;
+        Ok(())
+    }
+}
+
+/// Run a command on the running VM, with arguments as a JSON object, printing its result
+pub fn run(path: &Path, command: &str, arguments: Option<&str>) -> Result<(), String> {
+    let arguments = arguments
+        .map(|arguments| {
+            let value: Value = serde_json::from_str(arguments)
+                .map_err(|err| format!("invalid QMP arguments {arguments:?}: {err}"))?;
+            if !value.is_object() {
+                return Err(format!("QMP arguments {arguments:?} must be a JSON object"));
+            }
+            Ok(value)
+        })
+        .transpose()?;
+    let mut client = Client::connect(path)?;
+    let result = client.execute(command, arguments)?;
+    let output = serde_json::to_string_pretty(&result).map_err(|err| err.to_string())?;
+    println!("{output}");
+    Ok(())
+}
+
+/// Connection to the QMP socket of a running VM
+#[cfg(not(unix))]
+pub struct Client;
+
+#[cfg(not(unix))]
+impl Client {
+    pub fn connect(_path: &Path) -> Result<Self, String> {
+        Err("QMP sockets are only supported on Unix hosts".to_string())
+    }
+
+    pub fn execute(&mut self, _command: &str, _arguments: Option<Value>) -> Result<Value, String> {
+        unreachable!("QMP client cannot be created")
+    }
+
+    pub fn human_command(&mut self, _command_line: &str) -> Result<String, String> {
+        unreachable!("QMP client cannot be created")
+    }
+}
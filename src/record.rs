@@ -0,0 +1,91 @@
+use std::{
+    error::Error,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    output, qmp,
+    screenshot::{self, Ppm},
+    util,
+};
+
+/// Frames per second when none is given
+pub const DEFAULT_FPS: u32 = 10;
+
+/// Record the display of the running VM to a video at path, encoded by ffmpeg in the format
+/// of its extension, until enter is pressed or the VM exits
+pub fn run(qmp_path: &Path, path: &Path, fps: u32) -> Result<(), Box<dyn Error>> {
+    let ffmpeg = util::which("ffmpeg").ok_or("ffmpeg not found, please install it")?;
+    let mut client = qmp::Client::connect(qmp_path)?;
+
+    // Frames are scaled to the first one, as the encoder cannot change size when the guest
+    // changes resolution
+    let first = screenshot::dump(&mut client)?;
+    let ppm = Ppm::parse(&first)?;
+    let mut child = Command::new(ffmpeg)
+        .args(["-loglevel", "error", "-y"])
+        .args(["-f", "image2pipe", "-c:v", "ppm"])
+        .arg("-framerate")
+        .arg(fps.to_string())
+        .args(["-i", "-"])
+        .arg("-vf")
+        .arg(format!("scale={}:{}", ppm.width, ppm.height))
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().ok_or("failed to open ffmpeg input")?;
+
+    let (stop_tx, stop_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = io::stdin().read_line(&mut String::new());
+        let _ = stop_tx.send(());
+    });
+    output::status!(
+        "recording {}x{} at {fps} fps to {}, press enter to stop",
+        ppm.width,
+        ppm.height,
+        path.display()
+    );
+
+    // Frames are repeated when dumps are slower than the frame rate, to keep the video in
+    // real time
+    let interval = Duration::from_secs(1) / fps;
+    let start = Instant::now();
+    let mut frames = 0;
+    let mut frame = first;
+    let res = loop {
+        let due = (start.elapsed().as_secs_f64() * f64::from(fps)) as u64 + 1;
+        while frames < due {
+            stdin.write_all(&frame)?;
+            frames += 1;
+        }
+        if stop_rx.recv_timeout(interval).is_ok() {
+            break Ok(());
+        }
+        match screenshot::dump(&mut client) {
+            Ok(next) => frame = next,
+            Err(err) => break Err(err),
+        }
+    };
+
+    drop(stdin);
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("ffmpeg failed with {status}").into());
+    }
+    output::status!(
+        "saved {:.1} seconds to {}",
+        frames as f64 / f64::from(fps),
+        path.display()
+    );
+    // The video is still valid when the VM exits while recording
+    if let Err(err) = res {
+        log::warn!("recording stopped early: {err}");
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::{
+    error::Error,
+    fs,
+    io::{ErrorKind, Read},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{cache, image, output, util};
+
+/// File recording the size the harddrive was grown to
+pub fn size_path(hd_path: &Path) -> PathBuf {
+    hd_path.with_extension("size")
+}
+
+fn recorded_size(hd_path: &Path) -> Result<Option<u64>, Box<dyn Error>> {
+    let path = size_path(hd_path);
+    match fs::read_to_string(&path) {
+        Ok(data) => {
+            Ok(Some(data.trim().parse().map_err(|err| {
+                format!("invalid size in {path:?}: {err}")
+            })?))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Size of the disk seen by the guest
+fn virtual_size(hd_path: &Path, format: &str) -> Result<u64, Box<dyn Error>> {
+    if format == "qcow2" {
+        // Virtual size is stored big endian at offset 24 of the qcow2 header
+        let mut header = [0; 32];
+        fs::File::open(hd_path)?.read_exact(&mut header)?;
+        Ok(u64::from_be_bytes(header[24..32].try_into()?))
+    } else {
+        Ok(fs::metadata(hd_path)?.len())
+    }
+}
+
+/// Grow the harddrive to size, recording it so the harddrive is grown again when recreated
+pub fn run(hd_path: &Path, size: u64) -> Result<(), Box<dyn Error>> {
+    if !hd_path.is_file() {
+        return Err(format!("harddrive {hd_path:?} not found, run rebox to create it").into());
+    }
+
+    let format = image::format(hd_path)?;
+    let current = virtual_size(hd_path, format)?;
+    if size < current {
+        return Err(format!(
+            "harddrive is already {}, shrinking is not supported",
+            util::format_size(current)
+        )
+        .into());
+    }
+
+    output::status!(
+        "resizing {hd_path:?} from {} to {}",
+        util::format_size(current),
+        util::format_size(size)
+    );
+    if format == "qcow2" {
+        let qemu_img = util::which("qemu-img").ok_or("qemu-img not found, please install QEMU")?;
+        let status = Command::new(qemu_img)
+            .arg("resize")
+            .arg("-f")
+            .arg("qcow2")
+            .arg(hd_path)
+            .arg(size.to_string())
+            .status()?;
+        if !status.success() {
+            return Err(format!("qemu-img resize failed with {status}").into());
+        }
+    } else {
+        let file = fs::OpenOptions::new().write(true).open(hd_path)?;
+        file.set_len(size)?;
+        file.sync_all()?;
+    }
+
+    cache::write(&size_path(hd_path), format!("{size}\n"))?;
+    Ok(())
+}
+
+/// Grow a recreated raw harddrive to the recorded size
+pub fn restore(hd_path: &Path) -> Result<(), Box<dyn Error>> {
+    let Some(size) = recorded_size(hd_path)? else {
+        return Ok(());
+    };
+    if image::format(hd_path)? != "raw" {
+        return Ok(());
+    }
+    let file = fs::OpenOptions::new().write(true).open(hd_path)?;
+    if file.metadata()?.len() < size {
+        output::status!("growing harddrive to {}", util::format_size(size));
+        file.set_len(size)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::{fmt, process::Command, str::FromStr};
+
+/// Real time clock configuration, parsed from base=utc|localtime,clock=host|vm
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Rtc {
+    /// Start the clock in local time instead of UTC
+    pub localtime: bool,
+    /// Drive the clock from virtual time, which stops while the VM is paused, instead of the
+    /// host time, which keeps the guest in sync after the host is suspended
+    pub vm_clock: bool,
+}
+
+impl Rtc {
+    pub fn args(&self, command: &mut Command) {
+        command.arg("-rtc").arg(self.to_string());
+    }
+}
+
+impl fmt::Display for Rtc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let base = if self.localtime { "localtime" } else { "utc" };
+        let clock = if self.vm_clock { "vm" } else { "host" };
+        write!(f, "base={base},clock={clock}")
+    }
+}
+
+impl FromStr for Rtc {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rtc = Rtc::default();
+        for option in s.split(',') {
+            rtc = match option.split_once('=') {
+                Some(("base", "utc")) => Rtc {
+                    localtime: false,
+                    ..rtc
+                },
+                Some(("base", "localtime")) => Rtc {
+                    localtime: true,
+                    ..rtc
+                },
+                Some(("clock", "host")) => Rtc {
+                    vm_clock: false,
+                    ..rtc
+                },
+                Some(("clock", "vm")) => Rtc {
+                    vm_clock: true,
+                    ..rtc
+                },
+                _ => {
+                    return Err(format!(
+                        "invalid RTC option {option:?}, expected base=utc|localtime or \
+                         clock=host|vm"
+                    ))
+                }
+            };
+        }
+        Ok(rtc)
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    error::Error,
+    fs,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use crate::{output, qmp, util};
+
+/// Path of a new screenshot in the current directory
+pub fn default_path() -> PathBuf {
+    PathBuf::from(format!("screenshot-{}.png", util::timestamp()))
+}
+
+/// Image decoded from the binary PPM format written by QEMU
+pub struct Ppm {
+    pub width: u32,
+    pub height: u32,
+    /// RGB pixels, one byte per channel
+    data: Vec<u8>,
+}
+
+impl Ppm {
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        // Header is P6, width, height, and max value separated by whitespace, then one
+        // whitespace character before the pixels
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while bytes.get(pos).is_some_and(u8::is_ascii_whitespace) {
+                pos += 1;
+            }
+            let start = pos;
+            while bytes.get(pos).is_some_and(|b| !b.is_ascii_whitespace()) {
+                pos += 1;
+            }
+            if start == pos {
+                return Err("truncated PPM header".to_string());
+            }
+            fields.push(String::from_utf8_lossy(&bytes[start..pos]).to_string());
+        }
+        if fields[0] != "P6" || fields[3] != "255" {
+            return Err(format!(
+                "unsupported PPM format {} {}",
+                fields[0], fields[3]
+            ));
+        }
+        let parse = |field: &str| {
+            field
+                .parse::<u32>()
+                .map_err(|err| format!("invalid PPM size {field:?}: {err}"))
+        };
+        let width = parse(&fields[1])?;
+        let height = parse(&fields[2])?;
+        let len = width as usize * height as usize * 3;
+        let data = bytes
+            .get(pos + 1..pos + 1 + len)
+            .ok_or("truncated PPM pixels")?
+            .to_vec();
+        Ok(Ppm {
+            width,
+            height,
+            data,
+        })
+    }
+
+    fn write_png(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = fs::File::create(path)?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Dump the display of the running VM, returning it in the PPM format
+pub fn dump(client: &mut qmp::Client) -> Result<Vec<u8>, Box<dyn Error>> {
+    // QEMU writes the dump itself, in PPM as older versions cannot write PNG
+    let dump = tempfile::Builder::new().suffix(".ppm").tempfile()?;
+    client.screendump(dump.path())?;
+    Ok(fs::read(dump.path())?)
+}
+
+/// Save the display of the running VM as a PNG at path
+pub fn run(qmp_path: &Path, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut client = qmp::Client::connect(qmp_path)?;
+    let ppm = Ppm::parse(&dump(&mut client)?)?;
+    ppm.write_png(path)?;
+    output::status!(
+        "saved {}x{} screenshot to {}",
+        ppm.width,
+        ppm.height,
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use crate::{profile::Profile, util};
+
+/// Host side of a guest serial port
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Serial {
+    /// Terminal that rebox was started from
+    Stdio,
+    /// Output is written to a file
+    File(PathBuf),
+    /// Pseudo terminal, printed by QEMU, which tools like screen can attach to
+    Pty,
+    /// TCP server on localhost, which tools like telnet can connect to
+    Tcp(u16),
+    /// Serial port is not connected
+    None,
+}
+
+impl Serial {
+    /// Value of the QEMU -serial option
+    pub fn arg(&self) -> String {
+        match self {
+            Serial::Stdio => "stdio".to_string(),
+            Serial::File(path) => format!("file:{}", path.display()),
+            Serial::Pty => "pty".to_string(),
+            // Does not wait for a client, so the guest boots without one
+            Serial::Tcp(port) => format!("tcp:127.0.0.1:{port},server=on,wait=off"),
+            Serial::None => "none".to_string(),
+        }
+    }
+}
+
+/// Path of a new log of the serial console, in the directory of the profile if there is one
+pub fn log_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    let dir = match profile {
+        Some(profile) => profile.cache_dir(cache_dir),
+        None => cache_dir.to_path_buf(),
+    };
+    dir.join("logs")
+        .join(format!("serial-{}.log", util::timestamp()))
+}
+
+/// Connect a serial port to stdio, with its output also written to log
+pub fn stdio_args(command: &mut Command, log: &Path) {
+    command.arg("-chardev").arg(format!(
+        "stdio,id=serial-stdio,logfile={},logappend=on",
+        log.display()
+    ));
+    command.arg("-serial").arg("chardev:serial-stdio");
+}
+
+/// Path of the socket the serial console of a detached VM is connected to
+pub fn socket_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "serial")
+}
+
+/// Connect a serial port to a socket that tools like socat can attach to, with its output
+/// also written to log, for VMs without a terminal. The socket left behind by a previous run
+/// is removed
+pub fn socket_args(command: &mut Command, path: &Path, log: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    command.arg("-chardev").arg(format!(
+        "socket,id=serial-socket,path={},server=on,wait=off,logfile={},logappend=on",
+        path.display(),
+        log.display()
+    ));
+    command.arg("-serial").arg("chardev:serial-socket");
+    Ok(())
+}
+
+impl fmt::Display for Serial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Serial::Stdio => f.write_str("stdio"),
+            Serial::File(path) => write!(f, "file:{}", path.display()),
+            Serial::Pty => f.write_str("pty"),
+            Serial::Tcp(port) => write!(f, "tcp:{port}"),
+            Serial::None => f.write_str("none"),
+        }
+    }
+}
+
+impl FromStr for Serial {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(Serial::File(path.into())),
+            Some(("tcp", port)) => port
+                .parse()
+                .map(Serial::Tcp)
+                .map_err(|_| format!("invalid serial TCP port {port:?}")),
+            None if s == "stdio" => Ok(Serial::Stdio),
+            None if s == "pty" => Ok(Serial::Pty),
+            None if s == "none" => Ok(Serial::None),
+            _ => Err(format!(
+                "unknown serial {s:?}, expected one of stdio, file:<PATH>, pty, tcp:<PORT>, none"
+            )),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{profile::Profile, util};
+
+/// Host directory shared with the guest, which mounts it by tag
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Share {
+    pub path: PathBuf,
+    pub tag: String,
+}
+
+impl Share {
+    fn check(&self) -> Result<(), String> {
+        if !self.path.is_dir() {
+            return Err(format!(
+                "shared directory {} does not exist",
+                self.path.display()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Share {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.tag)
+    }
+}
+
+impl FromStr for Share {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split at the last colon, as the tag cannot contain one but the path may
+        match s.rsplit_once(':') {
+            Some((path, tag)) if !path.is_empty() && !tag.is_empty() => Ok(Share {
+                path: path.into(),
+                tag: tag.to_string(),
+            }),
+            _ => Err(format!("invalid share {s:?}, expected <PATH>:<TAG>")),
+        }
+    }
+}
+
+/// Share the directories with virtio-9p, which is handled by QEMU itself
+pub fn args_9p(command: &mut Command, shares: &[Share]) -> Result<(), String> {
+    for (i, share) in shares.iter().enumerate() {
+        share.check()?;
+        // Ownership and permissions of the guest are stored in extended attributes
+        command.arg("-fsdev").arg(format!(
+            "local,id=fs{i},path={},security_model=mapped-xattr",
+            share.path.display()
+        ));
+        command
+            .arg("-device")
+            .arg(format!("virtio-9p-pci,fsdev=fs{i},mount_tag={}", share.tag));
+    }
+    Ok(())
+}
+
+/// virtiofsd daemons serving the shared directories, stopped when dropped
+pub struct Virtiofsd {
+    children: Vec<Child>,
+    socket_paths: Vec<PathBuf>,
+}
+
+fn virtiofsd_path() -> Option<PathBuf> {
+    // Usually installed outside of PATH, as it is only run by QEMU or libvirt
+    util::which("virtiofsd").or_else(|| {
+        [
+            "/usr/libexec/virtiofsd",
+            "/usr/lib/qemu/virtiofsd",
+            "/usr/lib/virtiofsd",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+    })
+}
+
+/// Path of the socket virtiofsd listens on for the share at index i
+fn virtiofs_socket_path(cache_dir: &Path, profile: Option<&Profile>, i: usize) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, &format!("virtiofs{i}"))
+}
+
+/// Add the devices connected to virtiofsd for each directory, and share the guest memory of
+/// size bytes with virtiofsd
+pub fn args_virtiofs(
+    command: &mut Command,
+    shares: &[Share],
+    memory: u64,
+    cache_dir: &Path,
+    profile: Option<&Profile>,
+) -> Result<(), String> {
+    for (i, share) in shares.iter().enumerate() {
+        share.check()?;
+        command.arg("-chardev").arg(format!(
+            "socket,id=virtiofs{i},path={}",
+            virtiofs_socket_path(cache_dir, profile, i).display()
+        ));
+        command.arg("-device").arg(format!(
+            "vhost-user-fs-pci,chardev=virtiofs{i},tag={}",
+            share.tag
+        ));
+    }
+
+    // virtiofsd accesses the guest memory directly, so it must be shared memory
+    command.arg("-object").arg(format!(
+        "memory-backend-memfd,id=mem,size={memory},share=on"
+    ));
+    command.arg("-numa").arg("node,memdev=mem");
+    Ok(())
+}
+
+impl Virtiofsd {
+    /// Launch virtiofsd for each directory, and add the devices connected to them. The guest
+    /// memory of size bytes is shared with virtiofsd
+    pub fn start(
+        command: &mut Command,
+        shares: &[Share],
+        memory: u64,
+        cache_dir: &Path,
+        profile: Option<&Profile>,
+    ) -> Result<Self, String> {
+        let virtiofsd = virtiofsd_path()
+            .ok_or("virtiofsd not found, install it to use --virtiofs, like with `sudo apt install virtiofsd`")?;
+        args_virtiofs(command, shares, memory, cache_dir, profile)?;
+        let mut daemons = Virtiofsd {
+            children: Vec::new(),
+            socket_paths: Vec::new(),
+        };
+        for (i, share) in shares.iter().enumerate() {
+            let socket_path = virtiofs_socket_path(cache_dir, profile, i);
+            if let Some(parent) = socket_path.parent() {
+                fs::create_dir_all(parent)
+                    .map_err(|err| format!("failed to create {}: {err}", parent.display()))?;
+            }
+            match fs::remove_file(&socket_path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    return Err(format!("failed to remove {}: {err}", socket_path.display()))
+                }
+                _ => {}
+            }
+
+            let child = Command::new(&virtiofsd)
+                .arg(format!("--socket-path={}", socket_path.display()))
+                .arg(format!("--shared-dir={}", share.path.display()))
+                .stdin(Stdio::null())
+                .spawn()
+                .map_err(|err| format!("failed to run {}: {err}", virtiofsd.display()))?;
+            daemons.children.push(child);
+            daemons.socket_paths.push(socket_path.clone());
+
+            // QEMU fails to start if the socket is not ready
+            let start = Instant::now();
+            while !socket_path.exists() {
+                if let Some(child) = daemons.children.last_mut() {
+                    if let Ok(Some(status)) = child.try_wait() {
+                        return Err(format!("virtiofsd exited with {status}"));
+                    }
+                }
+                if start.elapsed() > Duration::from_secs(5) {
+                    return Err("timed out waiting for virtiofsd to start".to_string());
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+        Ok(daemons)
+    }
+}
+
+impl Drop for Virtiofsd {
+    fn drop(&mut self) {
+        // Already exited unless QEMU failed to connect
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        for socket_path in &self.socket_paths {
+            let _ = fs::remove_file(socket_path);
+        }
+    }
+}
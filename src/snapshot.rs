@@ -0,0 +1,65 @@
+use std::{error::Error, path::Path, process::Command};
+
+use crate::{args::SnapshotCommand, image, output, qmp, util};
+
+/// Fail unless the harddrive exists and is qcow2, which snapshots are stored in
+fn check_qcow2(hd_path: &Path) -> Result<(), Box<dyn Error>> {
+    if !hd_path.is_file() {
+        return Err(format!("harddrive {hd_path:?} not found, run rebox to create it").into());
+    }
+    if image::format(hd_path)? != "qcow2" {
+        return Err(format!(
+            "harddrive {hd_path:?} does not support snapshots, run rebox with --qcow2 to convert it"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Manage internal snapshots of a qcow2 harddrive using qemu-img
+pub fn run(command: &SnapshotCommand, hd_path: &Path) -> Result<(), Box<dyn Error>> {
+    check_qcow2(hd_path)?;
+
+    let qemu_img = util::which("qemu-img").ok_or("qemu-img not found, please install QEMU")?;
+    let mut qemu_img = Command::new(qemu_img);
+    qemu_img.arg("snapshot");
+    match command {
+        SnapshotCommand::Create { name } => qemu_img.arg("-c").arg(name),
+        SnapshotCommand::List => qemu_img.arg("-l"),
+        SnapshotCommand::Restore { name } => qemu_img.arg("-a").arg(name),
+        SnapshotCommand::Delete { name } => qemu_img.arg("-d").arg(name),
+    };
+    qemu_img.arg(hd_path);
+
+    let status = qemu_img.status()?;
+    if !status.success() {
+        return Err(format!("qemu-img snapshot failed with {status}").into());
+    }
+    Ok(())
+}
+
+/// Save the state of the running VM, including memory and devices, in a snapshot of the
+/// harddrive, or load it into the running VM if restore is set
+pub fn state(
+    qmp_path: &Path,
+    hd_path: &Path,
+    name: &str,
+    restore: bool,
+) -> Result<(), Box<dyn Error>> {
+    check_qcow2(hd_path)?;
+    let mut client = qmp::Client::connect(qmp_path)?;
+    let command = if restore { "loadvm" } else { "savevm" };
+    // Failures are reported in the output instead of as QMP errors
+    let output = client.human_command(&format!("{command} {name}"))?;
+    if !output.trim().is_empty() {
+        return Err(format!("{command} failed: {}", output.trim()).into());
+    }
+    if restore {
+        output::status!("restored state {name:?}");
+    } else {
+        output::status!(
+            "saved state {name:?}, run rebox with --load-state {name} to start from it"
+        );
+    }
+    Ok(())
+}
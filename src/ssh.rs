@@ -0,0 +1,115 @@
+use std::{
+    error::Error,
+    io::Read,
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{output, profile::Profile, qmp};
+
+/// Time to wait for the SSH server of the guest, which starts late in boot
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Host port forwarded to port 22 of the guest, from the output of `info usernet`
+fn forwarded_port(usernet: &str) -> Option<u16> {
+    // Forwards are listed like:
+    // TCP[HOST_FORWARD]  13  127.0.0.1  2222  10.0.2.15  22  0  0
+    usernet.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [state, _fd, _src, src_port, _dst, "22", ..] if *state == "TCP[HOST_FORWARD]" => {
+                src_port.parse().ok()
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Find a host port forwarded to the SSH port of the running VM, adding one if there is none
+fn ensure_forward(client: &mut qmp::Client) -> Result<u16, String> {
+    let usernet = client.human_command("info usernet")?;
+    if let Some(port) = forwarded_port(&usernet) {
+        return Ok(port);
+    }
+
+    // Let the host pick a free port
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .map_err(|err| format!("failed to find a free port: {err}"))?
+        .port();
+    let output = client.human_command(&format!("hostfwd_add net0 tcp:127.0.0.1:{port}-:22"))?;
+    if !output.trim().is_empty() {
+        return Err(format!("failed to forward port {port}: {}", output.trim()));
+    }
+    output::status!("forwarding port {port} to the SSH port of the guest");
+    Ok(port)
+}
+
+/// Check if the SSH server answers, as the forward accepts connections even without one
+fn ready(addr: &SocketAddr) -> bool {
+    let Ok(mut stream) = TcpStream::connect_timeout(addr, Duration::from_secs(1)) else {
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let mut banner = [0; 4];
+    stream.read_exact(&mut banner).is_ok() && &banner == b"SSH-"
+}
+
+/// Open an SSH session to the running VM, passing ssh_args to ssh
+pub fn run(
+    cache_dir: &Path,
+    profile: Option<&Profile>,
+    user: &str,
+    ssh_args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let mut client = qmp::Client::connect(&qmp::socket_path(cache_dir, profile))?;
+    let port = ensure_forward(&mut client)?;
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let start = Instant::now();
+    if !ready(&addr) {
+        output::status!("waiting for the SSH server of the guest");
+        while !ready(&addr) {
+            if start.elapsed() > TIMEOUT {
+                return Err(format!(
+                    "SSH server of the guest did not answer within {} seconds",
+                    TIMEOUT.as_secs()
+                )
+                .into());
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    // Host keys are kept apart from the user's, as they change when the harddrive is reset
+    let known_hosts = Profile::runtime_path(cache_dir, profile, "known_hosts");
+    let mut command = Command::new("ssh");
+    command
+        .arg("-p")
+        .arg(port.to_string())
+        .arg("-o")
+        .arg(format!("UserKnownHostsFile={}", known_hosts.display()))
+        .arg("-o")
+        .arg("StrictHostKeyChecking=accept-new")
+        .args(ssh_args)
+        .arg(format!("{user}@127.0.0.1"));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let err = command.exec();
+        Err(format!("failed to run ssh: {err}").into())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let status = command.status()?;
+        if !status.success() {
+            return Err(format!("ssh exited with {status}").into());
+        }
+        Ok(())
+    }
+}
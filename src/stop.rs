@@ -0,0 +1,45 @@
+use std::{
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{output, qmp};
+
+/// Time the guest has to shut down when none is given
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Ask the guest of the running VM to power off, quitting QEMU if it has not done so within
+/// timeout
+pub fn run(qmp_path: &Path, timeout: Duration) -> Result<(), String> {
+    let mut client = qmp::Client::connect(qmp_path)?;
+    client.system_powerdown()?;
+    output::status!(
+        "waiting up to {} seconds for the guest to shut down",
+        timeout.as_secs()
+    );
+
+    // QEMU closes the socket when it exits after the guest powers off
+    let start = Instant::now();
+    loop {
+        thread::sleep(Duration::from_millis(500));
+        match client.query_status() {
+            // QEMU stays open after the guest powers off when run with -no-shutdown
+            Ok(status) if status == "shutdown" => break,
+            Ok(_) if start.elapsed() >= timeout => {
+                log::warn!("guest did not shut down in time, quitting QEMU");
+                break;
+            }
+            Ok(_) => {}
+            Err(_) => {
+                output::status!("guest shut down");
+                return Ok(());
+            }
+        }
+    }
+
+    // The connection may be closed by QEMU before it responds
+    let _ = client.quit();
+    output::status!("stopped");
+    Ok(())
+}
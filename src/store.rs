@@ -0,0 +1,158 @@
+use serde_json::{json, Value};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{cache, output, util};
+
+/// Directory of downloads named by their SHA-256 hash, so identical downloads are only stored
+/// once and different versions never collide
+pub fn dir(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("store").join("sha256")
+}
+
+/// Path of the download with a hash, which may not exist
+pub fn path(cache_dir: &Path, sha256: &str) -> PathBuf {
+    dir(cache_dir).join(sha256)
+}
+
+fn index_path(cache_dir: &Path) -> PathBuf {
+    cache_dir.join("store").join("index.json")
+}
+
+/// Download in the store, as recorded in the index
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Kind of download: image or qemu
+    pub kind: String,
+    /// File name it was downloaded as, like qemu-9.0.1.tar.xz
+    pub name: String,
+    pub arch: Option<String>,
+    /// Build date of an image or version of QEMU
+    pub version: Option<String>,
+    pub url: String,
+    /// Hash of the download, or None when fetching a download that has no known hash
+    pub sha256: Option<String>,
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        json!({
+            "kind": self.kind,
+            "name": self.name,
+            "arch": self.arch,
+            "version": self.version,
+            "url": self.url,
+            "sha256": self.sha256,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let string = |key: &str| value.get(key)?.as_str().map(str::to_string);
+        Some(Entry {
+            kind: string("kind")?,
+            name: string("name")?,
+            arch: string("arch"),
+            version: string("version"),
+            url: string("url")?,
+            sha256: Some(string("sha256")?),
+        })
+    }
+}
+
+/// Entries of the index, which is empty before anything is stored
+pub fn index(cache_dir: &Path) -> io::Result<Vec<Entry>> {
+    let data = match fs::read_to_string(index_path(cache_dir)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let value: Value = serde_json::from_str(&data).map_err(io::Error::other)?;
+    Ok(value
+        .as_array()
+        .map(|entries| entries.iter().filter_map(Entry::from_json).collect())
+        .unwrap_or_default())
+}
+
+/// Add an entry to the index, replacing the entry for the same URL
+fn record(cache_dir: &Path, entry: Entry) -> io::Result<()> {
+    let mut entries = index(cache_dir)?;
+    entries.retain(|other| other.url != entry.url);
+    entries.push(entry);
+    let value = Value::Array(entries.iter().map(Entry::to_json).collect());
+    cache::write(
+        &index_path(cache_dir),
+        serde_json::to_string_pretty(&value).map_err(io::Error::other)?,
+    )
+}
+
+/// Path of the stored download of url with a hash, or with the hash it was last stored with
+/// if none is given, if it exists
+pub fn cached(cache_dir: &Path, url: &str, sha256: Option<&str>) -> Option<PathBuf> {
+    let path = match sha256 {
+        Some(sha256) => path(cache_dir, sha256),
+        None => {
+            let entry = index(cache_dir)
+                .ok()?
+                .into_iter()
+                .find(|entry| entry.url == url)?;
+            path(cache_dir, entry.sha256.as_deref()?)
+        }
+    };
+    path.is_file().then_some(path)
+}
+
+/// Move a download cached before the store was used to its path in the store
+pub fn adopt(legacy: &Path, path: &Path) -> io::Result<()> {
+    if legacy.is_file() && !path.exists() {
+        output::status!("moving {} into the store", legacy.display());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(legacy, path)?;
+    }
+    Ok(())
+}
+
+/// Download the file for an entry into the store if it is not there yet, returning its path
+/// and hash. A download with a known hash is verified, and legacy is the path it may have been
+/// cached at before the store was used
+pub fn fetch(cache_dir: &Path, mut entry: Entry, legacy: &Path) -> io::Result<(PathBuf, String)> {
+    fs::create_dir_all(dir(cache_dir))?;
+    let sha256 = match &entry.sha256 {
+        Some(sha256) => {
+            let path = path(cache_dir, sha256);
+            adopt(legacy, &path)?;
+            util::sha256_or_download(&entry.url, sha256, &path)?;
+            sha256.clone()
+        }
+        None => match cached(cache_dir, &entry.url, None) {
+            Some(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            None => {
+                // Named after the URL until the hash is known, so an interrupted download is
+                // continued by the next run
+                let download = if legacy.is_file() {
+                    legacy.to_path_buf()
+                } else {
+                    let tmp_dir = cache_dir.join("store").join("tmp");
+                    fs::create_dir_all(&tmp_dir)?;
+                    tmp_dir.join(util::sha256(&mut entry.url.as_bytes())?)
+                };
+                let sha256 = if download.is_file() {
+                    util::sha256_progress(&download)?
+                } else {
+                    util::download_progress(&entry.url, &download)?
+                };
+                fs::rename(&download, path(cache_dir, &sha256))?;
+                sha256
+            }
+        },
+    };
+    entry.sha256 = Some(sha256.clone());
+    record(cache_dir, entry)?;
+    Ok((path(cache_dir, &sha256), sha256))
+}
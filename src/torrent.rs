@@ -0,0 +1,58 @@
+use std::{error::Error, fs, path::Path, process::Command};
+
+use crate::{cache, http, output, util};
+
+/// Try to download url to path from the torrent published next to it, at url.torrent, leaving
+/// path missing if that fails so it is downloaded over HTTP instead
+///
+/// aria2c does the BitTorrent download, and stops seeding as soon as it is complete. The hash
+/// of path is checked by the caller like for any other download
+pub fn download(url: &str, path: &Path) {
+    if let Err(err) = download_with_aria2c(url, path) {
+        log::warn!("failed to download {url} over BitTorrent, falling back to HTTP: {err}");
+    }
+}
+
+fn download_with_aria2c(url: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+    let aria2c = util::which("aria2c").ok_or("aria2c not found, please install aria2")?;
+    let torrent_url = format!("{url}.torrent");
+
+    // The torrent decides the name of the file, so it is downloaded into its own directory.
+    // Interrupted downloads are continued from the control files aria2c keeps there
+    let dir = cache::partial_path(&path.with_extension("torrent"));
+    fs::create_dir_all(&dir)?;
+
+    output::status!("downloading {torrent_url} with aria2c");
+    let mut command = Command::new(aria2c);
+    command
+        .arg("--follow-torrent=mem")
+        .arg("--seed-time=0")
+        .arg("--bt-stop-timeout=60")
+        .arg("--continue=true")
+        .arg("--summary-interval=0")
+        .arg("--console-log-level=warn")
+        .arg("--dir")
+        .arg(&dir);
+    if let Some(rate) = http::limit_rate() {
+        command.arg(format!("--max-overall-download-limit={rate}"));
+    }
+    command.arg(&torrent_url);
+    let status = command.status()?;
+    if !status.success() {
+        return Err(format!("aria2c exited with {status}").into());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let file = entry?.path();
+        if file.is_file() && file.extension().is_none_or(|ext| ext != "aria2") {
+            files.push(file);
+        }
+    }
+    let [file] = files.as_slice() else {
+        return Err(format!("expected one file in {torrent_url}, found {}", files.len()).into());
+    };
+    cache::commit(file, path)?;
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{arch::Arch, profile::Profile};
+
+/// Path of the socket swtpm listens on for a profile, or the VM without a profile
+pub fn socket_path(cache_dir: &Path, profile: Option<&Profile>) -> PathBuf {
+    Profile::runtime_path(cache_dir, profile, "tpm")
+}
+
+/// Add the TPM device connected to swtpm listening at socket_path
+pub fn args(command: &mut Command, arch: Arch, socket_path: &Path) -> Result<(), String> {
+    let device = match arch {
+        Arch::X86_64 | Arch::I686 => "tpm-tis",
+        Arch::Aarch64 => "tpm-tis-device",
+        Arch::Riscv64gc => return Err(format!("TPM is not supported for {arch}")),
+    };
+    command
+        .arg("-chardev")
+        .arg(format!("socket,id=chrtpm,path={}", socket_path.display()));
+    command
+        .arg("-tpmdev")
+        .arg("emulator,id=tpm0,chardev=chrtpm");
+    command.arg("-device").arg(format!("{device},tpmdev=tpm0"));
+    Ok(())
+}
+
+/// TPM 2.0 emulator connected to the guest, stopped when dropped
+pub struct Swtpm {
+    child: Child,
+}
+
+impl Swtpm {
+    /// Launch swtpm with the state of the profile, and add the TPM device connected to it
+    pub fn start(
+        command: &mut Command,
+        arch: Arch,
+        cache_dir: &Path,
+        profile: Option<&Profile>,
+    ) -> Result<Self, String> {
+        let socket_path = socket_path(cache_dir, profile);
+        args(command, arch, &socket_path)?;
+
+        let state_dir = match profile {
+            Some(profile) => profile.cache_dir(cache_dir).join("tpm"),
+            None => cache_dir.join("tpm"),
+        };
+        fs::create_dir_all(&state_dir)
+            .map_err(|err| format!("failed to create {}: {err}", state_dir.display()))?;
+        if let Some(parent) = socket_path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| format!("failed to create {}: {err}", parent.display()))?;
+        }
+        match fs::remove_file(&socket_path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                return Err(format!("failed to remove {}: {err}", socket_path.display()))
+            }
+            _ => {}
+        }
+
+        // Terminates on its own when QEMU disconnects
+        let child = Command::new("swtpm")
+            .arg("socket")
+            .arg("--tpm2")
+            .arg("--terminate")
+            .arg("--tpmstate")
+            .arg(format!("dir={}", state_dir.display()))
+            .arg("--ctrl")
+            .arg(format!("type=unixio,path={}", socket_path.display()))
+            .stdin(Stdio::null())
+            .spawn()
+            .map_err(|err| match err.kind() {
+                ErrorKind::NotFound => {
+                    "swtpm not found, install it to use --tpm, like with `sudo apt install swtpm`"
+                        .to_string()
+                }
+                _ => format!("failed to run swtpm: {err}"),
+            })?;
+        let mut swtpm = Swtpm { child };
+
+        // QEMU fails to start if the socket is not ready
+        let start = Instant::now();
+        while !socket_path.exists() {
+            if let Ok(Some(status)) = swtpm.child.try_wait() {
+                return Err(format!("swtpm exited with {status}"));
+            }
+            if start.elapsed() > Duration::from_secs(5) {
+                return Err("timed out waiting for swtpm to start".to_string());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        Ok(swtpm)
+    }
+}
+
+impl Drop for Swtpm {
+    fn drop(&mut self) {
+        // Already exited unless QEMU failed to connect
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
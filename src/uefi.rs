@@ -0,0 +1,103 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{arch::Arch, cache, output, profile::Profile, qemu};
+
+/// Secure Boot firmware code and a variable store template with the default keys enrolled, as
+/// installed by distribution OVMF packages. QEMU only provides the code without enrolled keys
+const SECURE_BOOT_FIRMWARE: &[(&str, &str)] = &[
+    // Debian and Ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.secboot.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.ms.fd",
+    ),
+    (
+        "/usr/share/OVMF/OVMF_CODE.secboot.fd",
+        "/usr/share/OVMF/OVMF_VARS.ms.fd",
+    ),
+    // Fedora
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.secboot.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.secboot.fd",
+    ),
+    // openSUSE
+    (
+        "/usr/share/qemu/ovmf-x86_64-smm-ms-code.bin",
+        "/usr/share/qemu/ovmf-x86_64-smm-ms-vars.bin",
+    ),
+];
+
+fn secure_boot_firmware() -> Result<(PathBuf, PathBuf), String> {
+    SECURE_BOOT_FIRMWARE
+        .iter()
+        .map(|(code, vars)| (PathBuf::from(code), PathBuf::from(vars)))
+        .find(|(code, vars)| code.is_file() && vars.is_file())
+        .ok_or_else(|| {
+            "Secure Boot firmware with enrolled keys not found, please install OVMF, like with \
+             `sudo apt install ovmf`"
+                .to_string()
+        })
+}
+
+/// Boot with UEFI firmware loaded as pflash, with a variable store kept for the profile. With
+/// secure_boot, the firmware enforces signatures using the enrolled default keys
+pub fn args(
+    command: &mut Command,
+    arch: Arch,
+    secure_boot: bool,
+    qemu_version: Option<&str>,
+    cache_dir: &Path,
+    profile: Option<&Profile>,
+) -> Result<(), Box<dyn Error>> {
+    let (code_path, template_path) = if secure_boot {
+        secure_boot_firmware()?
+    } else {
+        let (code, vars_template) = arch.uefi_firmware();
+        (
+            qemu::firmware(qemu_version, code, cache_dir)?,
+            qemu::firmware(qemu_version, vars_template, cache_dir)?,
+        )
+    };
+
+    // Boot entries and settings are written to the variable store, so each VM has its own.
+    // The store must match the firmware it was created for
+    let vars_name = if secure_boot {
+        "efivars-secure"
+    } else {
+        "efivars"
+    };
+    let vars_path = match profile {
+        Some(profile) => profile.cache_dir(cache_dir).join(format!("{vars_name}.fd")),
+        None => cache_dir.join(format!("{vars_name}-{arch}.fd")),
+    };
+    if !vars_path.is_file() {
+        output::status!("creating UEFI variable store {}", vars_path.display());
+        if let Some(parent) = vars_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial_path = cache::partial_path(&vars_path);
+        fs::copy(&template_path, &partial_path)?;
+        cache::commit(&partial_path, &vars_path)?;
+    }
+
+    if secure_boot {
+        // Only code running in SMM may write the variable store, so the guest cannot
+        // disable Secure Boot
+        command
+            .arg("-global")
+            .arg("driver=cfi.pflash01,property=secure,value=on");
+    }
+    command.arg("-drive").arg(format!(
+        "if=pflash,format=raw,unit=0,readonly=on,file={}",
+        code_path.display()
+    ));
+    command.arg("-drive").arg(format!(
+        "if=pflash,format=raw,unit=1,file={}",
+        vars_path.display()
+    ));
+    Ok(())
+}
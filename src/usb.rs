@@ -0,0 +1,111 @@
+use std::{
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+/// Host USB device passed through to the guest
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UsbDevice {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl UsbDevice {
+    /// Find the device node of the first matching device connected to the host
+    fn node(&self) -> Option<PathBuf> {
+        let read_hex = |dir: &Path, name: &str| -> Option<u16> {
+            let value = fs::read_to_string(dir.join(name)).ok()?;
+            u16::from_str_radix(value.trim(), 16).ok()
+        };
+        let read_num = |dir: &Path, name: &str| -> Option<u32> {
+            fs::read_to_string(dir.join(name)).ok()?.trim().parse().ok()
+        };
+        for entry in fs::read_dir("/sys/bus/usb/devices").ok()?.flatten() {
+            let dir = entry.path();
+            if read_hex(&dir, "idVendor") != Some(self.vendor)
+                || read_hex(&dir, "idProduct") != Some(self.product)
+            {
+                continue;
+            }
+            let bus = read_num(&dir, "busnum")?;
+            let dev = read_num(&dir, "devnum")?;
+            return Some(PathBuf::from(format!("/dev/bus/usb/{bus:03}/{dev:03}")));
+        }
+        None
+    }
+
+    /// Check that the device is connected and can be opened by QEMU, with instructions for
+    /// fixing access if not
+    pub fn check_access(&self) -> Result<(), String> {
+        if !cfg!(target_os = "linux") {
+            log::info!("unable to check access to USB device {self} on this host");
+            return Ok(());
+        }
+        let Some(node) = self.node() else {
+            return Err(format!("USB device {self} is not connected"));
+        };
+        match fs::OpenOptions::new().read(true).write(true).open(&node) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => Err(format!(
+                "you do not have access to USB device {self} at {}. Allow it with a udev rule \
+                 like `SUBSYSTEM==\"usb\", ATTR{{idVendor}}==\"{:04x}\", \
+                 ATTR{{idProduct}}==\"{:04x}\", MODE=\"0666\"` in \
+                 /etc/udev/rules.d/50-rebox.rules, then reconnect the device",
+                node.display(),
+                self.vendor,
+                self.product
+            )),
+            Err(err) => Err(format!(
+                "failed to open USB device {self} at {}: {err}",
+                node.display()
+            )),
+        }
+    }
+}
+
+/// Add a USB controller unless the machine already has one, a tablet if requested, and pass
+/// through the devices
+pub fn args(command: &mut Command, devices: &[UsbDevice], tablet: bool, has_controller: bool) {
+    if devices.is_empty() && !tablet {
+        return;
+    }
+    if !has_controller {
+        command.arg("-device").arg("qemu-xhci");
+    }
+    if tablet {
+        // Absolute pointer, so the cursor moves between host and guest without grabbing
+        command.arg("-device").arg("usb-tablet");
+    }
+    for device in devices {
+        command.arg("-device").arg(format!(
+            "usb-host,vendorid=0x{:04x},productid=0x{:04x}",
+            device.vendor, device.product
+        ));
+    }
+}
+
+impl fmt::Display for UsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
+
+impl FromStr for UsbDevice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid USB device {s:?}, expected vendorid:productid in hex");
+        let (vendor, product) = s.split_once(':').ok_or_else(invalid)?;
+        let parse = |id: &str| {
+            let id = id.strip_prefix("0x").unwrap_or(id);
+            u16::from_str_radix(id, 16).map_err(|_| invalid())
+        };
+        Ok(UsbDevice {
+            vendor: parse(vendor)?,
+            product: parse(product)?,
+        })
+    }
+}
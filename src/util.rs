@@ -1,17 +1,23 @@
-use pbr::{self, ProgressBar};
-use reqwest;
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
 use std::fs;
-use std::io::{Error, ErrorKind, Read, Result, Write};
-use std::path::Path;
+use std::io::{self, Error, ErrorKind, IsTerminal, Read, Result, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
 use std::time::Duration;
 
-use crate::progress_bar::{ProgressBarRead, ProgressBarWrite};
+use crate::cache;
+use crate::http;
+use crate::output;
+use crate::progress_bar::{Progress, ProgressBarRead, ProgressBarWrite};
 
 pub fn download_length(url: &str) -> reqwest::Result<Option<u64>> {
-    let client = reqwest::blocking::Client::new();
+    let client = http::client();
 
-    let resp = client.head(url).send()?.error_for_status()?;
+    let resp = http::retry(url, || client.head(url).send()?.error_for_status())?;
 
     Ok(resp
         .headers()
@@ -20,33 +26,269 @@ pub fn download_length(url: &str) -> reqwest::Result<Option<u64>> {
         .and_then(|len| len.parse().ok()))
 }
 
-pub fn download<W: Write>(url: &str, w: &mut W) -> reqwest::Result<u64> {
-    let mut resp = reqwest::blocking::get(url)?.error_for_status()?;
-    resp.copy_to(w)
+/// Request the part of url after the data already in f and write it to f, hashing it
+fn download_rest(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    f: &mut fs::File,
+    hasher: &mut Sha256,
+    pb: &mut Progress,
+) -> Result<()> {
+    let offset = f.stream_position()?;
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={offset}-"));
+    }
+    let mut resp = request
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .map_err(Error::other)?;
+    if offset > 0 && resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        log::info!("server does not support range requests, restarting download");
+        *hasher = Sha256::new();
+        f.set_len(0)?;
+        f.seek(SeekFrom::Start(0))?;
+        pb.set(0);
+    }
+
+    let mut hw = Sha256Write {
+        hasher: mem::take(hasher),
+        w: f,
+    };
+    let res = {
+        let mut pbw = ProgressBarWrite::new(pb, &mut hw);
+        resp.copy_to(&mut http::Throttled(&mut pbw))
+            .map_err(Error::other)
+    };
+    *hasher = hw.hasher;
+    res.map(|_| ())
+}
+
+/// Download url to path with a progress bar, returning the SHA-256 hash of the file
+///
+/// Data is written to a partial file that is kept if the download is interrupted, so the
+/// next download continues from where it stopped with a range request. Mirrors of the
+/// server are tried first, continuing the partial file when one fails
+pub fn download_progress<P: AsRef<Path>>(url: &str, path: P) -> Result<String> {
+    http::check_online(url)?;
+    let urls = http::mirror_urls(url);
+    let (last, mirrors) = urls.split_last().expect("url is always included");
+    for mirror in mirrors {
+        match download_from(mirror, path.as_ref()) {
+            Ok(hash) => return Ok(hash),
+            Err(err) => log::warn!("download from {mirror} failed, trying the next server: {err}"),
+        }
+    }
+    download_from(last, path.as_ref())
 }
 
-pub fn download_progress<P: AsRef<Path>>(url: &str, path: P) -> Result<u64> {
+fn download_from(url: &str, path: &Path) -> Result<String> {
     let len = download_length(url)
-        .map_err(|err| Error::new(ErrorKind::Other, err))?
-        .ok_or(Error::new(ErrorKind::Other, "ContentLength not found"))?;
+        .map_err(Error::other)?
+        .ok_or(Error::other("ContentLength not found"))?;
+
+    let partial = cache::partial_path(path);
+    let connections = http::connections();
+    if connections > 1 && len >= 2 * MIN_SEGMENT && !partial.exists() {
+        if let Some(hash) = download_segmented(url, path, len, connections)? {
+            return Ok(hash);
+        }
+    }
+    let mut f = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&partial)?;
+    let mut offset = f.metadata()?.len();
+    if offset > len {
+        // Left behind by a download of a different file
+        offset = 0;
+    }
+
+    // Data already downloaded is hashed again, as the hash state is not kept, and the
+    // rest is hashed as it is written
+    let mut hasher = Sha256::new();
+    if offset > 0 {
+        output::status!("resuming download at {}", format_size(offset));
+        io::copy(&mut (&mut f).take(offset), &mut hasher)?;
+    }
+    f.set_len(offset)?;
+    f.seek(SeekFrom::Start(offset))?;
+
+    let mut pb = Progress::new("download", len);
+    pb.set(offset);
+
+    // Interrupted downloads are retried from where they stopped. Nothing is requested if
+    // only renaming the partial file was interrupted
+    let client = http::client();
+    let mut attempt = 0;
+    let res = loop {
+        if f.stream_position()? == len {
+            break Ok(());
+        }
+        match download_rest(&client, url, &mut f, &mut hasher, &mut pb) {
+            Err(err)
+                if err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<reqwest::Error>())
+                    .is_some_and(|err| http::wait_to_retry(url, &mut attempt, err)) => {}
+            res => break res,
+        }
+    };
 
-    let mut f = fs::File::create(&path)?;
+    pb.finish();
 
-    let mut pb = ProgressBar::new(len);
-    pb.message("download: ");
-    pb.set_max_refresh_rate(Some(Duration::new(1, 0)));
-    pb.set_units(pbr::Units::Bytes);
+    res?;
+    let downloaded = f.stream_position()?;
+    if downloaded != len {
+        return Err(Error::other(format!(
+            "download of {url:?} ended after {} of {}",
+            format_size(downloaded),
+            format_size(len)
+        )));
+    }
+    let hash = format!("{:x}", hasher.finalize());
+    cache::commit(&partial, path)?;
+    Ok(hash)
+}
 
-    let res = {
-        let mut pbw = ProgressBarWrite::new(&mut pb, &mut f);
-        download(url, &mut pbw).map_err(|err| Error::new(ErrorKind::Other, err))
-    };
+/// Smallest part of a file that is downloaded over its own connection
+const MIN_SEGMENT: u64 = 8 * 1024 * 1024;
 
-    pb.finish_println("");
+/// Writer for one segment of a download, counting the bytes written by all segments
+struct SegmentWrite<'a> {
+    f: &'a mut fs::File,
+    pos: &'a mut u64,
+    done: &'a AtomicU64,
+}
 
-    f.sync_all()?;
+impl Write for SegmentWrite<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let count = self.f.write(buf)?;
+        *self.pos += count as u64;
+        self.done.fetch_add(count as u64, Ordering::Relaxed);
+        http::throttle(count as u64);
+        Ok(count)
+    }
 
-    res
+    fn flush(&mut self) -> Result<()> {
+        self.f.flush()
+    }
+}
+
+/// Download the bytes from start to end of url into the same place in partial, continuing
+/// from where it stopped after transient errors
+fn download_segment(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    partial: &Path,
+    (start, end): (u64, u64),
+    done: &AtomicU64,
+) -> Result<()> {
+    let mut f = fs::OpenOptions::new().write(true).open(partial)?;
+    let mut pos = start;
+    let mut attempt = 0;
+    while pos < end {
+        f.seek(SeekFrom::Start(pos))?;
+        let res = client
+            .get(url)
+            .header(reqwest::header::RANGE, format!("bytes={pos}-{}", end - 1))
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .map_err(Error::other)
+            .and_then(|mut resp| {
+                if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                    return Err(Error::other("server ignored range request"));
+                }
+                let mut w = SegmentWrite {
+                    f: &mut f,
+                    pos: &mut pos,
+                    done,
+                };
+                resp.copy_to(&mut w).map_err(Error::other)
+            });
+        match res {
+            Ok(_) if pos < end => {
+                return Err(Error::other(format!(
+                    "segment of {url:?} ended after {} of {}",
+                    format_size(pos - start),
+                    format_size(end - start)
+                )))
+            }
+            Ok(_) => {}
+            Err(err)
+                if err
+                    .get_ref()
+                    .and_then(|err| err.downcast_ref::<reqwest::Error>())
+                    .is_some_and(|err| http::wait_to_retry(url, &mut attempt, err)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Download url of length len to path over up to connections parallel range requests,
+/// returning the SHA-256 hash of the file, or None if the server does not support range
+/// requests
+///
+/// Segments are written in place, so the partial file has holes until all of them finish. It
+/// is kept separate from the partial file of a single stream download, which can be resumed
+fn download_segmented(
+    url: &str,
+    path: &Path,
+    len: u64,
+    connections: u64,
+) -> Result<Option<String>> {
+    let client = http::client();
+    let probe = http::retry(url, || {
+        client
+            .get(url)
+            .header(reqwest::header::RANGE, "bytes=0-0")
+            .send()?
+            .error_for_status()
+    })
+    .map_err(Error::other)?;
+    if probe.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        log::info!("server does not support range requests, downloading over one connection");
+        return Ok(None);
+    }
+
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".segments");
+    let partial = cache::partial_path(&path.with_file_name(name));
+    fs::File::create(&partial)?.set_len(len)?;
+
+    let segments = connections.min(len / MIN_SEGMENT);
+    output::status!("downloading over {segments} connections");
+    let mut pb = Progress::new("download", len);
+
+    let done = AtomicU64::new(0);
+    let res = thread::scope(|scope| {
+        let handles: Vec<_> = (0..segments)
+            .map(|i| {
+                let range = (len * i / segments, len * (i + 1) / segments);
+                let (client, partial, done) = (&client, &partial, &done);
+                scope.spawn(move || download_segment(client, url, partial, range, done))
+            })
+            .collect();
+        while !handles.iter().all(|handle| handle.is_finished()) {
+            pb.set(done.load(Ordering::Relaxed));
+            thread::sleep(Duration::from_millis(200));
+        }
+        handles.into_iter().try_for_each(|handle| {
+            handle
+                .join()
+                .unwrap_or_else(|_| Err(Error::other("download thread panicked")))
+        })
+    });
+    pb.set(done.load(Ordering::Relaxed));
+    pb.finish();
+    res?;
+
+    let hash = sha256_progress(&partial)?;
+    cache::commit(&partial, path)?;
+    Ok(Some(hash))
 }
 
 pub fn extract<R: Read, P: AsRef<Path>>(r: &mut R, dst: P) -> Result<()> {
@@ -61,17 +303,14 @@ pub fn extract_progress<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Resul
 
     let mut r = fs::File::open(&src)?;
 
-    let mut pb = ProgressBar::new(len);
-    pb.message("extract: ");
-    pb.set_max_refresh_rate(Some(Duration::new(1, 0)));
-    pb.set_units(pbr::Units::Bytes);
+    let mut pb = Progress::new("extract", len);
 
     let res = {
         let mut pbr = ProgressBarRead::new(&mut pb, &mut r);
         extract(&mut pbr, dst)
     };
 
-    pb.finish_println("");
+    pb.finish();
 
     res
 }
@@ -97,17 +336,14 @@ pub fn sha256_progress<P: AsRef<Path>>(path: P) -> Result<String> {
 
     let mut f = fs::File::open(&path)?;
 
-    let mut pb = ProgressBar::new(len);
-    pb.message("verify: ");
-    pb.set_max_refresh_rate(Some(Duration::new(1, 0)));
-    pb.set_units(pbr::Units::Bytes);
+    let mut pb = Progress::new("verify", len);
 
     let res = {
         let mut pbr = ProgressBarRead::new(&mut pb, &mut f);
         sha256(&mut pbr)
     };
 
-    pb.finish_println("");
+    pb.finish();
 
     res
 }
@@ -115,20 +351,19 @@ pub fn sha256_progress<P: AsRef<Path>>(path: P) -> Result<String> {
 pub fn sha256_or_download<P: AsRef<Path>>(url: &str, sha256: &str, path: P) -> Result<()> {
     let path = path.as_ref();
     if path.exists() {
-        let path_sha256 = sha256_progress(&path)?;
+        let path_sha256 = sha256_progress(path)?;
         if path_sha256 == sha256 {
             // File already exists and matches hash
             return Ok(());
         } else {
             log::warn!("previous file at {path:?} has hash {path_sha256:?} instead of {sha256:?}");
             // Remove file that does not match hash
-            fs::remove_file(&path)?;
+            fs::remove_file(path)?;
         }
     }
 
     // Download file
-    download_progress(url, &path)?;
-    let path_sha256 = sha256_progress(&path)?;
+    let path_sha256 = download_progress(url, path)?;
     if path_sha256 == sha256 {
         // Downloaded file matches hash
         Ok(())
@@ -136,7 +371,7 @@ pub fn sha256_or_download<P: AsRef<Path>>(url: &str, sha256: &str, path: P) -> R
         let message = format!("downloaded file from {url:?} to {path:?} has hash {path_sha256:?} instead of {sha256:?}");
         log::error!("{}", message);
         // Remove file that does not match hash
-        fs::remove_file(&path)?;
+        fs::remove_file(path)?;
         Err(Error::new(ErrorKind::InvalidData, message))
     }
 }
@@ -146,25 +381,524 @@ pub fn zstd_decompress<R: Read, W: Write>(r: &mut R, w: &mut W) -> Result<()> {
     Ok(())
 }
 
-pub fn zstd_decompress_progress<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<()> {
+/// Decompress input into output, returning the SHA-256 hash of the output
+pub fn zstd_decompress_progress<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+) -> Result<String> {
     let len = fs::metadata(&input)?.len();
 
     let mut r = fs::File::open(&input)?;
     let mut w = fs::File::create(&output)?;
 
-    let mut pb = ProgressBar::new(len);
-    pb.message("decompress: ");
-    pb.set_max_refresh_rate(Some(Duration::new(1, 0)));
-    pb.set_units(pbr::Units::Bytes);
+    let mut pb = Progress::new("decompress", len);
 
     let res = {
         let mut pbr = ProgressBarRead::new(&mut pb, &mut r);
-        zstd_decompress(&mut pbr, &mut w)
+        let mut sw = Sha256Write::new(&mut w);
+        zstd_decompress(&mut pbr, &mut sw).map(|()| sw.finalize())
     };
 
-    pb.finish_println("");
+    pb.finish();
 
     w.sync_all()?;
 
     res
 }
+
+/// Writer that hashes everything written through it
+pub struct Sha256Write<'w, W: Write + 'w> {
+    hasher: Sha256,
+    w: &'w mut W,
+}
+
+impl<'w, W: Write> Sha256Write<'w, W> {
+    pub fn new(w: &'w mut W) -> Sha256Write<'w, W> {
+        Sha256Write {
+            hasher: Sha256::new(),
+            w,
+        }
+    }
+
+    pub fn finalize(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<'w, W: Write> Write for Sha256Write<'w, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let count = self.w.write(buf)?;
+        self.hasher.update(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Ask the user a yes or no question, answering no if stdin is not a terminal
+pub fn confirm(question: &str) -> Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return Ok(false);
+    }
+
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    stdin.read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Format a byte count using binary units
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", units[unit])
+    }
+}
+
+/// Format a number of seconds with its two largest units, like 3d 4h
+pub fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs / 60 % 60),
+        _ => format!("{}d {}h", secs / 86400, secs / 3600 % 24),
+    }
+}
+
+/// Parse a size like 512M or 8G into bytes, using binary units
+pub fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let shift = match s[digits.len()..].to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size {s:?}, unknown unit")),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|err| format!("invalid size {s:?}: {err}"))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("invalid size {s:?}, too large"))
+}
+
+/// Parse a duration like 30d, 12h, 2w, or 90m
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let seconds = match &s[digits.len()..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration {s:?}, expected a unit of s, m, h, d, or w"
+            ))
+        }
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|err| format!("invalid duration {s:?}: {err}"))?;
+    value
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {s:?}, too large"))
+}
+
+/// Current UTC time like 20240131-235959, for naming files
+pub fn timestamp() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date from days since 1970-01-01, counting years from March so leap days are last
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Quote an argument for a POSIX shell, leaving it as is if it has no special characters
+pub fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=,:+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Find an executable in PATH
+pub fn which<P: AsRef<Path>>(name: P) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(&name))
+        .find(|path| path.is_file())
+}
+
+/// Weak checksum of a block that can be rolled forward one byte at a time, as used by rsync
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in block.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        RollingChecksum { a, b, len }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Move the block forward by one byte, removing old and adding new
+    fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(old as u32))
+            .wrapping_add(self.a);
+    }
+}
+
+/// Block index published next to a file at {url}.blocks
+///
+/// The first line is the block size and the length of the file, followed by a line for each
+/// block with its rolling checksum in hex and its SHA-256 hash
+struct BlockIndex {
+    block_size: usize,
+    len: u64,
+    blocks: Vec<(u32, String)>,
+}
+
+impl BlockIndex {
+    fn parse(data: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid block index");
+        let mut lines = data.lines();
+        let (block_size, len) = lines
+            .next()
+            .and_then(|line| line.split_once(' '))
+            .ok_or_else(invalid)?;
+        let block_size: usize = block_size.parse().map_err(|_| invalid())?;
+        let len: u64 = len.parse().map_err(|_| invalid())?;
+        let mut blocks = Vec::new();
+        for line in lines {
+            let (weak, strong) = line.split_once(' ').ok_or_else(invalid)?;
+            let weak = u32::from_str_radix(weak, 16).map_err(|_| invalid())?;
+            blocks.push((weak, strong.to_string()));
+        }
+        if block_size == 0 || blocks.len() as u64 != len.div_ceil(block_size as u64) {
+            return Err(invalid());
+        }
+        Ok(BlockIndex {
+            block_size,
+            len,
+            blocks,
+        })
+    }
+}
+
+/// Search old for blocks of the index, at any offset, writing them into file
+///
+/// Returns which blocks were found
+fn reuse_blocks(index: &BlockIndex, old: &Path, file: &mut fs::File) -> Result<Vec<bool>> {
+    let block_size = index.block_size;
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, (weak, _)) in index.blocks.iter().enumerate() {
+        // The last block may be short, so it is always downloaded
+        if (i as u64 + 1) * (block_size as u64) <= index.len {
+            by_weak.entry(*weak).or_default().push(i);
+        }
+    }
+
+    let mut found = vec![false; index.blocks.len()];
+    let mut r = fs::File::open(old)?;
+    // Window into old, refilled as the block moves forward
+    let mut buf = Vec::with_capacity(4 * block_size);
+    let mut pos = 0;
+    let mut rolling: Option<RollingChecksum> = None;
+    loop {
+        if pos + block_size > buf.len() {
+            buf.drain(..pos);
+            pos = 0;
+            let start = buf.len();
+            buf.resize(4 * block_size, 0);
+            let mut filled = start;
+            while filled < buf.len() {
+                let count = r.read(&mut buf[filled..])?;
+                if count == 0 {
+                    break;
+                }
+                filled += count;
+            }
+            buf.truncate(filled);
+            if buf.len() < block_size {
+                break;
+            }
+        }
+
+        let block = &buf[pos..pos + block_size];
+        let checksum = rolling.get_or_insert_with(|| RollingChecksum::new(block));
+        let mut matched = false;
+        if let Some(candidates) = by_weak.get(&checksum.digest()) {
+            let strong = format!("{:x}", Sha256::digest(block));
+            for &i in candidates {
+                if !found[i] && index.blocks[i].1 == strong {
+                    file.seek(SeekFrom::Start(i as u64 * block_size as u64))?;
+                    file.write_all(block)?;
+                    found[i] = true;
+                    matched = true;
+                }
+            }
+        }
+
+        if matched {
+            // Skip past the matched block and start a new checksum
+            pos += block_size;
+            rolling = None;
+        } else if pos + block_size < buf.len() {
+            checksum.roll(buf[pos], buf[pos + block_size]);
+            pos += 1;
+        } else {
+            // Refill before rolling into the next byte
+            pos += 1;
+            rolling = None;
+        }
+    }
+    Ok(found)
+}
+
+/// Download url to path, copying the blocks that are also in old instead of downloading them
+///
+/// Returns false if no block index is published for url. The result must be verified by the
+/// caller, as blocks are only checked against the index
+pub fn download_delta<P: AsRef<Path>, Q: AsRef<Path>>(url: &str, old: P, path: Q) -> Result<bool> {
+    http::check_online(url)?;
+    let client = http::client();
+    let index_url = format!("{url}.blocks");
+    let resp = http::failover(&index_url, |url| client.get(url).send()).map_err(Error::other)?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    let index = resp
+        .error_for_status()
+        .and_then(|resp| resp.text())
+        .map_err(Error::other)?;
+    let index = BlockIndex::parse(&index)?;
+
+    let mut file = fs::File::create(&path)?;
+    file.set_len(index.len)?;
+    let found = reuse_blocks(&index, old.as_ref(), &mut file)?;
+
+    // Download missing blocks, joining neighbours into a single range
+    let block_size = index.block_size as u64;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for (i, found) in found.iter().enumerate() {
+        if *found {
+            continue;
+        }
+        let start = i as u64 * block_size;
+        let end = (start + block_size).min(index.len);
+        match ranges.last_mut() {
+            Some(range) if range.1 == start => range.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    let missing: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+    output::status!(
+        "reusing {} of {}, downloading {}",
+        format_size(index.len - missing),
+        format_size(index.len),
+        format_size(missing)
+    );
+
+    let mut pb = Progress::new("download", missing);
+    let res = (|| -> Result<()> {
+        for (start, end) in ranges {
+            let mut resp = http::failover(url, |url| {
+                client
+                    .get(url)
+                    .header(reqwest::header::RANGE, format!("bytes={start}-{}", end - 1))
+                    .send()?
+                    .error_for_status()
+            })
+            .map_err(Error::other)?;
+            if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+                return Err(Error::other("server does not support range requests"));
+            }
+            file.seek(SeekFrom::Start(start))?;
+            let mut pbw = ProgressBarWrite::new(&mut pb, &mut file);
+            let count = resp
+                .copy_to(&mut http::Throttled(&mut pbw))
+                .map_err(Error::other)?;
+            if count != end - start {
+                return Err(Error::other("range request returned the wrong length"));
+            }
+        }
+        Ok(())
+    })();
+    pb.finish();
+    res?;
+
+    file.sync_all()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_checksum() {
+        let data = b"the quick brown fox jumps over the lazy dog";
+        let mut rolling = RollingChecksum::new(&data[..16]);
+        for start in 1..=data.len() - 16 {
+            rolling.roll(data[start - 1], data[start + 15]);
+            assert_eq!(
+                rolling.digest(),
+                RollingChecksum::new(&data[start..start + 16]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn block_index() {
+        let index = BlockIndex::parse("4 10\n1a strong1\n2b strong2\nff strong3\n").unwrap();
+        assert_eq!(index.block_size, 4);
+        assert_eq!(index.len, 10);
+        assert_eq!(
+            index.blocks,
+            [
+                (0x1a, "strong1".to_string()),
+                (0x2b, "strong2".to_string()),
+                (0xff, "strong3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_index_empty() {
+        let index = BlockIndex::parse("4096 0\n").unwrap();
+        assert!(index.blocks.is_empty());
+    }
+
+    #[test]
+    fn block_index_invalid() {
+        for data in [
+            "",
+            "4096",
+            "0 0",
+            "x 10\n1a strong",
+            "4 10\n1a strong1\n2b strong2",
+            "4 4\n1a strong1\n2b strong2",
+            "4 4\nzz strong",
+            "4 4\n1a",
+        ] {
+            let err = BlockIndex::parse(data).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512B"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4 << 10));
+        assert_eq!(parse_size("64MiB"), Ok(64 << 20));
+        assert_eq!(parse_size("2GB"), Ok(2 << 30));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("-4G").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("16777216T").is_err());
+    }
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert_eq!(
+            parse_duration("2w"),
+            Ok(Duration::from_secs(14 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn parse_duration_invalid() {
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("30D").is_err());
+        assert!(parse_duration("30y").is_err());
+        assert!(parse_duration(&format!("{}w", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn shell_quote_plain() {
+        assert_eq!(shell_quote("-m"), "-m");
+        assert_eq!(
+            shell_quote("file=/tmp/a.img,format=raw"),
+            "file=/tmp/a.img,format=raw"
+        );
+    }
+
+    #[test]
+    fn shell_quote_special() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
@@ -0,0 +1,55 @@
+use std::{error::Error, fs, path::Path, process::Command};
+
+use crate::util;
+
+/// Verify a detached OpenPGP signature of data, made by the key in key_path, using gpg
+pub fn signature(data: &[u8], signature: &[u8], key_path: &Path) -> Result<(), Box<dyn Error>> {
+    let gpg = util::which("gpg").ok_or("gpg not found, please install GnuPG")?;
+
+    // Temporary home directory, so only the given key is trusted. It is created with a random
+    // name only the user can access, and removed when dropped
+    let tmp = tempfile::tempdir()?;
+    let home = tmp.path();
+    let data_path = home.join("data");
+    let signature_path = home.join("data.sig");
+    fs::write(&data_path, data)?;
+    fs::write(&signature_path, signature)?;
+
+    let output = Command::new(&gpg)
+        .arg("--batch")
+        .arg("--homedir")
+        .arg(home)
+        .arg("--import")
+        .arg(key_path)
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "failed to import signing key {key_path:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let output = Command::new(&gpg)
+        .arg("--batch")
+        .arg("--homedir")
+        .arg(home)
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--verify")
+        .arg(&signature_path)
+        .arg(&data_path)
+        .output()?;
+    // VALIDSIG is only reported for a good signature made by an imported key
+    let valid = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.starts_with("[GNUPG:] VALIDSIG "));
+    if !output.status.success() || !valid {
+        return Err(format!(
+            "bad signature: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    process::Command,
+};
+
+/// Generate a password for the VNC server, which only uses the first 8 characters
+pub fn generate_password() -> io::Result<String> {
+    // The server may listen beyond localhost, so the password must not be guessable
+    let mut bytes = [0; 4];
+    getrandom::getrandom(&mut bytes).map_err(|err| io::Error::other(err.to_string()))?;
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Parse a VNC display like :1, which listens on port 5901
+pub fn parse_display(s: &str) -> Result<u16, String> {
+    s.strip_prefix(':')
+        .and_then(|display| display.parse().ok())
+        .ok_or_else(|| format!("invalid VNC display {s:?}, expected :<NUMBER> like :1"))
+}
+
+/// Show the guest over VNC on the display at the listen address, requiring the password in
+/// password_path if one is given, so it is not visible in the QEMU command line
+pub fn args(command: &mut Command, display: u16, listen: &str, password_path: Option<&Path>) {
+    let mut vnc = format!("{listen}:{display}");
+    if let Some(password_path) = password_path {
+        command.arg("-object").arg(format!(
+            "secret,id=vncpass,file={}",
+            password_path.display()
+        ));
+        vnc.push_str(",password-secret=vncpass");
+    }
+    command.arg("-vnc").arg(vnc);
+}
+
+/// Write the password to the file passed to QEMU
+pub fn write_password(password_path: &Path, password: &str) -> io::Result<()> {
+    if let Some(parent) = password_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    write_private(password_path, password)
+}
+
+/// Write a file that only the user can read, replacing the file of a previous run as its
+/// permissions are only set when it is created
+fn write_private(path: &Path, data: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(path)?, data.as_bytes())
+}